@@ -0,0 +1,52 @@
+//! Short critical sections under contention, in nanoseconds per lock/unlock,
+//! for an increasing number of threads hammering the same lock.
+//!
+//! Run with `cargo bench --bench contended`.
+
+use std::{hint::black_box, sync::Barrier, thread, time::Instant};
+
+use lock_learning::{
+    mutex::Mutex,
+    queue_lock::{ClhLock, McsLock},
+    spin::{SpinLock, TicketLock},
+};
+
+const ITERATIONS: u32 = 200_000;
+const THREADS: [u32; 4] = [1, 2, 4, 8];
+
+/// Runs `f` `ITERATIONS` times spread over `threads` threads, which start together.
+fn bench(name: &str, threads: u32, f: impl Fn() + Sync) {
+    let barrier = Barrier::new(threads as usize + 1);
+    // Before the barrier, since on few cores the threads may be done
+    // before the main thread gets to run again.
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                barrier.wait();
+                for _ in 0..ITERATIONS / threads {
+                    f();
+                }
+            });
+        }
+        barrier.wait();
+    });
+    let ns = start.elapsed().as_nanos() as f64 / ITERATIONS as f64;
+    println!("{name:<12} {threads:>2} threads {ns:>8.1} ns");
+}
+
+fn main() {
+    for threads in THREADS {
+        let lock = SpinLock::new(0u64);
+        bench("SpinLock", threads, || *black_box(&lock).lock() += 1);
+        let lock = TicketLock::new(0u64);
+        bench("TicketLock", threads, || *black_box(&lock).lock() += 1);
+        let lock = McsLock::new(0u64);
+        bench("McsLock", threads, || *black_box(&lock).lock() += 1);
+        let lock = ClhLock::new(0u64);
+        bench("ClhLock", threads, || *black_box(&lock).lock() += 1);
+        let lock = Mutex::new(0u64);
+        bench("Mutex", threads, || *black_box(&lock).lock().unwrap() += 1);
+        println!();
+    }
+}
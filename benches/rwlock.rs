@@ -0,0 +1,40 @@
+//! Uncontended `RwLock` lock/unlock round trips, in nanoseconds per iteration.
+//!
+//! Run with `cargo bench --bench rwlock`.
+
+use std::{hint::black_box, time::Instant};
+
+use lock_learning::{membarrier, read_write_lock::RwLock};
+
+const ITERATIONS: u32 = 10_000_000;
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    // Warm up.
+    for _ in 0..ITERATIONS / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let ns = start.elapsed().as_nanos() as f64 / ITERATIONS as f64;
+    println!("{name:<20} {ns:>8.1} ns");
+}
+
+fn main() {
+    let lock = RwLock::new(0u64);
+    bench("write", || *lock.write().unwrap() += 1);
+    bench("read", || {
+        black_box(*lock.read().unwrap());
+    });
+    bench("write + read", || {
+        *lock.write().unwrap() += 1;
+        black_box(*lock.read().unwrap());
+    });
+
+    let lock = membarrier::RwLock::from_raw(membarrier::RawRwLock::new(), 0u64);
+    bench("membarrier read", || {
+        black_box(*lock.read().unwrap());
+    });
+    bench("membarrier write", || *lock.write().unwrap() += 1);
+}
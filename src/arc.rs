@@ -1,6 +1,6 @@
 use std::{
     cell::UnsafeCell,
-    mem::ManuallyDrop,
+    mem::{self, ManuallyDrop},
     ops::Deref,
     ptr::NonNull,
     sync::atomic::{
@@ -140,6 +140,38 @@ impl<T> Arc<T> {
         }
     }
 
+    /// Whether both point to the same allocation.
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.ptr == b.ptr
+    }
+
+    /// A pointer to the data, valid for as long as there's an `Arc`.
+    pub fn as_ptr(arc: &Self) -> *const T {
+        // `ManuallyDrop` is `repr(transparent)`.
+        arc.data().data.get().cast()
+    }
+
+    /// Turns the `Arc` into a pointer to the data, without dropping it.
+    /// `from_raw()` turns it back.
+    pub fn into_raw(arc: Self) -> *const T {
+        let arc = ManuallyDrop::new(arc);
+        Self::as_ptr(&arc)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw()`, and each pointer returned
+    /// by `into_raw()` may only be turned back once.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        let data = ptr
+            .cast::<u8>()
+            .sub(mem::offset_of!(ArcData<T>, data))
+            .cast::<ArcData<T>>();
+        Self {
+            ptr: NonNull::new_unchecked(data.cast_mut()),
+        }
+    }
+
     fn data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
     }
@@ -206,7 +238,9 @@ impl<T> Drop for Weak<T> {
 
 impl<T> Drop for Arc<T> {
     fn drop(&mut self) {
-        if self.data().data_ref_count.fetch_sub(1, Relaxed) == 1 {
+        // Release, like `Weak::drop()`, so whatever this `Arc` was used for
+        // happens before the data is dropped by the last one.
+        if self.data().data_ref_count.fetch_sub(1, Release) == 1 {
             fence(Acquire);
 
             // Safety: The data reference counter is zero,
@@ -227,7 +261,8 @@ impl<T> Drop for Arc<T> {
 
 #[cfg(test)]
 mod test {
-    #[deny(warnings)]
+    #![deny(warnings)]
+
     use std::sync::atomic::AtomicUsize;
     use std::sync::atomic::Ordering::Relaxed;
 
@@ -0,0 +1,270 @@
+//! An `Arc` that can be replaced atomically, for data that is read all the time
+//! but rarely changes: readers get their own `Arc` to the current value without
+//! taking a lock, and writers publish a new value without waiting for them to
+//! be done with the old one (read-copy-update). Readers that shouldn't keep
+//! an old value alive after it was replaced can get a `Weak` with `load_weak()`.
+//!
+//! The hard part is that `load()` reads the pointer and only then increments
+//! the reference count. In between, a writer could swap the pointer and drop
+//! the last `Arc`, so the reader would increment a freed count. So writers wait
+//! for such readers before they give the old `Arc` away. Readers announce
+//! themselves in one of two counters, picked by `epoch`. A writer swaps the
+//! pointer, flips `epoch`, and waits for the counter of the old epoch to drop to
+//! zero. Readers that come later use the other counter, so a steady stream of
+//! them can't keep a writer waiting, and they already see the new pointer.
+
+use std::{
+    hint,
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    sync::atomic::{
+        AtomicPtr, AtomicUsize,
+        Ordering::{Acquire, Relaxed, Release, SeqCst},
+    },
+    thread,
+};
+
+use crate::{
+    arc::{Arc, Weak},
+    lock_api,
+    mutex::RawMutex,
+};
+
+pub struct ArcSwap<T> {
+    /// From `Arc::into_raw()`, so it holds one reference.
+    ptr: AtomicPtr<T>,
+    /// Only changed by writers, while holding `writer`.
+    epoch: AtomicUsize,
+    /// Readers between reading `ptr` and having their own reference,
+    /// by the parity of the epoch they started in.
+    readers: [AtomicUsize; 2],
+    /// Writers take turns, so only one of them waits for readers at a time.
+    writer: lock_api::Mutex<RawMutex, ()>,
+    /// Behaves like the `Arc` it holds, for `Send` and `Sync`.
+    _marker: PhantomData<Arc<T>>,
+}
+
+impl<T> ArcSwap<T> {
+    pub fn new(value: Arc<T>) -> Self {
+        Self {
+            ptr: AtomicPtr::new(Arc::into_raw(value).cast_mut()),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writer: lock_api::Mutex::new(()),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn from_pointee(value: T) -> Self {
+        Self::new(Arc::new(value))
+    }
+
+    /// Returns the current value, without locking.
+    pub fn load(&self) -> Arc<T> {
+        self.with_current(Arc::clone)
+    }
+
+    /// Returns a `Weak` to the current value, without locking, and without
+    /// keeping the value alive once it's replaced.
+    pub fn load_weak(&self) -> Weak<T> {
+        self.with_current(Arc::downgrade)
+    }
+
+    /// Calls `f` with the `Arc` that `ptr` holds, which stays alive until `f` returns.
+    fn with_current<R>(&self, f: impl FnOnce(&Arc<T>) -> R) -> R {
+        // Check the epoch again after announcing ourselves, so a writer
+        // that flipped it in between can't miss us. All SeqCst, together
+        // with `wait_for_readers()`: if our second load still sees the old
+        // epoch, the writer's check of our counter comes after our increment.
+        let epoch = loop {
+            let epoch = self.epoch.load(SeqCst);
+            self.readers[epoch % 2].fetch_add(1, SeqCst);
+            if self.epoch.load(SeqCst) == epoch {
+                break epoch;
+            }
+            self.readers[epoch % 2].fetch_sub(1, Release);
+        };
+        let ptr = self.ptr.load(SeqCst);
+        // Safety: A writer that swapped `ptr` away waits for us
+        // before it lets go of its reference.
+        let current = ManuallyDrop::new(unsafe { Arc::from_raw(ptr) });
+        let r = f(&current);
+        // Release matches the Acquire in `wait_for_readers()`,
+        // so our increment happens before the writer's drop.
+        self.readers[epoch % 2].fetch_sub(1, Release);
+        r
+    }
+
+    pub fn store(&self, new: Arc<T>) {
+        drop(self.swap(new));
+    }
+
+    /// Replaces the value, and returns the old one.
+    pub fn swap(&self, new: Arc<T>) -> Arc<T> {
+        let _writer = self.writer.lock();
+        let old = self.ptr.swap(Arc::into_raw(new).cast_mut(), SeqCst);
+        self.wait_for_readers();
+        // Safety: `ptr` held this reference, and now we do.
+        unsafe { Arc::from_raw(old) }
+    }
+
+    /// Replaces the value with `new` only if it is still `current`.
+    ///
+    /// Returns the value from before, which is `current` if it was replaced.
+    pub fn compare_and_swap(&self, current: &Arc<T>, new: Arc<T>) -> Arc<T> {
+        let _writer = self.writer.lock();
+        let ptr = self.ptr.load(Relaxed);
+        if ptr.cast_const() != Arc::as_ptr(current) {
+            // Safety: Only writers replace `ptr`, and we're the only one.
+            return unsafe { clone_raw(ptr) };
+        }
+        self.ptr.store(Arc::into_raw(new).cast_mut(), SeqCst);
+        self.wait_for_readers();
+        unsafe { Arc::from_raw(ptr) }
+    }
+
+    /// Replaces the value with `f(old)`, and returns the old value.
+    ///
+    /// `f` may be called more than once, if another writer was faster.
+    pub fn rcu(&self, mut f: impl FnMut(&T) -> T) -> Arc<T> {
+        let mut current = self.load();
+        loop {
+            let new = Arc::new(f(&current));
+            let previous = self.compare_and_swap(&current, new);
+            if Arc::ptr_eq(&previous, &current) {
+                return previous;
+            }
+            current = previous;
+        }
+    }
+
+    /// Must only be called while holding `writer`, after replacing `ptr`.
+    fn wait_for_readers(&self) {
+        let epoch = self.epoch.load(Relaxed);
+        self.epoch.store(epoch.wrapping_add(1), SeqCst);
+        let mut attempt = 0u32;
+        // Readers only stay for an increment, unless they got preempted.
+        while self.readers[epoch % 2].load(Acquire) > 0 {
+            if attempt < 100 {
+                hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+            attempt = attempt.saturating_add(1);
+        }
+    }
+
+    pub fn into_inner(self) -> Arc<T> {
+        let this = ManuallyDrop::new(self);
+        // Safety: `this` is never used or dropped again.
+        unsafe { Arc::from_raw(this.ptr.load(Relaxed)) }
+    }
+}
+
+impl<T> Drop for ArcSwap<T> {
+    fn drop(&mut self) {
+        // Safety: `ptr` holds a reference, and nobody can load it anymore.
+        drop(unsafe { Arc::from_raw(*self.ptr.get_mut()) });
+    }
+}
+
+/// A new `Arc` from a pointer from `Arc::into_raw()`, leaving the original alone.
+unsafe fn clone_raw<T>(ptr: *const T) -> Arc<T> {
+    let arc = ManuallyDrop::new(Arc::from_raw(ptr));
+    Arc::clone(&arc)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed},
+        thread,
+    };
+
+    use super::ArcSwap;
+    use crate::arc::Arc;
+
+    #[test]
+    fn arc_swap() {
+        let config = ArcSwap::from_pointee(1);
+        assert_eq!(*config.load(), 1);
+
+        let one = config.swap(Arc::new(2));
+        assert_eq!(*one, 1);
+        let two = config.load();
+        assert_eq!(*two, 2);
+
+        // Not the current value anymore, so nothing happens.
+        let now = config.compare_and_swap(&one, Arc::new(3));
+        assert!(Arc::ptr_eq(&now, &two));
+        let now = config.compare_and_swap(&two, Arc::new(3));
+        assert!(Arc::ptr_eq(&now, &two));
+        assert_eq!(*config.load(), 3);
+
+        config.store(Arc::new(4));
+        assert_eq!(*config.rcu(|n| n * 10), 4);
+
+        // A `Weak` doesn't keep a replaced value alive.
+        let weak = config.load_weak();
+        assert_eq!(weak.upgrade().as_deref(), Some(&40));
+        let forty = config.swap(Arc::new(5));
+        assert!(weak.upgrade().is_some());
+        drop(forty);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(*config.into_inner(), 5);
+    }
+
+    #[test]
+    fn readers_and_writers() {
+        static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+        struct Snapshot(usize);
+
+        impl Snapshot {
+            fn new(n: usize) -> Self {
+                LIVE.fetch_add(1, Relaxed);
+                Self(n)
+            }
+        }
+
+        impl Drop for Snapshot {
+            fn drop(&mut self) {
+                LIVE.fetch_sub(1, Relaxed);
+            }
+        }
+
+        let config = ArcSwap::from_pointee(Snapshot::new(0));
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut last = 0;
+                    while !done.load(Relaxed) {
+                        let snapshot = config.load();
+                        assert!(snapshot.0 >= last);
+                        last = snapshot.0;
+                    }
+                });
+            }
+            let writers: Vec<_> = (0..2)
+                .map(|_| {
+                    s.spawn(|| {
+                        for _ in 0..1000 {
+                            drop(config.rcu(|old| Snapshot::new(old.0 + 1)));
+                        }
+                    })
+                })
+                .collect();
+            for w in writers {
+                w.join().unwrap();
+            }
+            done.store(true, Relaxed);
+        });
+        assert_eq!(config.load().0, 2000);
+        // All old snapshots are gone, including the ones
+        // `rcu()` made but couldn't store.
+        assert_eq!(LIVE.load(Relaxed), 1);
+        drop(config);
+        assert_eq!(LIVE.load(Relaxed), 0);
+    }
+}
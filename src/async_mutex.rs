@@ -0,0 +1,283 @@
+//! A mutex for async code: `lock()` returns a future instead of blocking the thread.
+//!
+//! It doesn't need any runtime, it only uses the `Waker` from the `Context`.
+
+use std::{
+    cell::UnsafeCell,
+    collections::VecDeque,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::atomic::{
+        AtomicBool, AtomicU32,
+        Ordering::{Acquire, Relaxed, Release},
+    },
+    task::{Context, Poll, Waker},
+};
+
+use crate::{arc::Arc, spin::SpinLock};
+
+pub struct Mutex<T> {
+    /// State to indicate Lock:
+    /// - 0: unlocked
+    /// - 1: locked, no futures waiting
+    /// - 2: locked, futures waiting in `waiters`
+    ///
+    /// Moving away from 2 only happens with `waiters` locked.
+    state: AtomicU32,
+    /// Waiting futures, oldest first.
+    waiters: SpinLock<VecDeque<Arc<Waiter>>>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for Mutex<T> where T: Send {}
+
+struct Waiter {
+    /// Set (with `waiters` locked) when an unlock hands the lock to this waiter.
+    granted: AtomicBool,
+    waker: SpinLock<Option<Waker>>,
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { self.mutex.unlock() }
+    }
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            waiters: SpinLock::new(VecDeque::new()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Resolves once the lock is ours.
+    ///
+    /// Waiting futures get the lock in order. Dropping the future gives up its place.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            waiter: None,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state.compare_exchange(0, 1, Acquire, Relaxed).ok()?;
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// Safety: the lock must be held by the caller.
+    unsafe fn unlock(&self) {
+        if self.state.compare_exchange(1, 0, Release, Relaxed).is_ok() {
+            return;
+        }
+        // State is 2: instead of unlocking, hand the lock over to the oldest waiter.
+        let mut waiters = self.waiters.lock();
+        let Some(next) = waiters.pop_front() else {
+            // All waiters were dropped.
+            self.state.store(0, Release);
+            return;
+        };
+        if waiters.is_empty() {
+            self.state.store(1, Relaxed);
+        }
+        // Still with `waiters` locked, so a dropped `Lock` can't miss it.
+        // Release, so the new owner sees what we did with the data.
+        next.granted.store(true, Release);
+        drop(waiters);
+        let waker = next.waker.lock().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The future returned by `Mutex::lock()`.
+pub struct Lock<'a, T> {
+    mutex: &'a Mutex<T>,
+    /// Our place in the queue, once we had to wait.
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mutex = this.mutex;
+
+        if let Some(waiter) = &this.waiter {
+            if !waiter.granted.load(Acquire) {
+                *waiter.waker.lock() = Some(cx.waker().clone());
+                // The unlock might have happened before we stored the waker.
+                if !waiter.granted.load(Acquire) {
+                    return Poll::Pending;
+                }
+            }
+            this.waiter = None;
+            return Poll::Ready(MutexGuard { mutex });
+        }
+
+        // Same fast path as `mutex::Mutex::lock`.
+        if let Some(guard) = mutex.try_lock() {
+            return Poll::Ready(guard);
+        }
+
+        let mut waiters = mutex.waiters.lock();
+        let mut s = mutex.state.load(Relaxed);
+        loop {
+            let r = match s {
+                // Nobody is waiting when it's unlocked, so it's ours.
+                0 => match mutex.state.compare_exchange(0, 1, Acquire, Relaxed) {
+                    Ok(_) => return Poll::Ready(MutexGuard { mutex }),
+                    Err(e) => Err(e),
+                },
+                // Make the unlock look at the queue.
+                1 => mutex.state.compare_exchange(1, 2, Relaxed, Relaxed),
+                _ => break,
+            };
+            match r {
+                Ok(_) => break,
+                Err(e) => s = e,
+            }
+        }
+        let waiter = Arc::new(Waiter {
+            granted: AtomicBool::new(false),
+            waker: SpinLock::new(Some(cx.waker().clone())),
+        });
+        waiters.push_back(waiter.clone());
+        this.waiter = Some(waiter);
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Lock<'_, T> {
+    fn drop(&mut self) {
+        let Some(waiter) = self.waiter.take() else {
+            return;
+        };
+        let mut waiters = self.mutex.waiters.lock();
+        if waiter.granted.load(Acquire) {
+            // We got the lock but nobody will use it, pass it on.
+            drop(waiters);
+            unsafe { self.mutex.unlock() }
+        } else {
+            waiters.retain(|w| !Arc::ptr_eq(w, &waiter));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::Arc,
+        task::{Context, Poll, Wake, Waker},
+        thread::{self, Thread},
+    };
+
+    use super::Mutex;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// For polling by hand, where nothing needs to be woken.
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// A minimal executor: park the thread until the waker is used.
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let mut f = pin!(f);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match f.as_mut().poll(&mut cx) {
+                Poll::Ready(r) => return r,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn async_mutex() {
+        let m = Mutex::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        block_on(async {
+                            let mut g = m.lock().await;
+                            *g += 1;
+                        });
+                    }
+                });
+            }
+        });
+        assert_eq!(m.into_inner(), 4000);
+    }
+
+    #[test]
+    fn handoff_and_cancel() {
+        let m = Mutex::new(0);
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+
+        let g = m.try_lock().unwrap();
+        let mut a = Box::pin(m.lock());
+        let mut b = Box::pin(m.lock());
+        assert!(a.as_mut().poll(&mut cx).is_pending());
+        assert!(b.as_mut().poll(&mut cx).is_pending());
+
+        // The unlock hands the lock to `a`, so it's not up for grabs.
+        drop(g);
+        assert!(m.try_lock().is_none());
+        let Poll::Ready(mut g) = a.as_mut().poll(&mut cx) else {
+            panic!("a should have the lock");
+        };
+        *g += 1;
+        drop(g);
+
+        // `b` got it next, dropping `b` without polling passes it on.
+        assert!(m.try_lock().is_none());
+        drop(b);
+        assert_eq!(*m.try_lock().unwrap(), 1);
+    }
+}
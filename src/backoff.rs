@@ -0,0 +1,142 @@
+//! How long a lock spins before it goes to sleep.
+//!
+//! Every lock holds a `&'static dyn SpinPolicy` that can be changed per instance,
+//! for example `Mutex::new(0).with_spin_policy(&NoSpin)`.
+
+use std::thread;
+
+pub trait SpinPolicy: Sync {
+    /// Waits a bit before the lock is tried again. `attempt` counts from 0.
+    ///
+    /// Returns `false` once spinning is no longer worth it, without waiting.
+    /// Blocking locks then go to sleep, while `SpinLock` yields its time slice
+    /// and calls this again.
+    fn backoff(&self, attempt: u32) -> bool;
+}
+
+/// One `spin_loop()` per attempt, for `limit` attempts.
+///
+/// `Spin { limit: 100 }` is what `Mutex` does by default.
+pub struct Spin {
+    pub limit: u32,
+}
+
+impl SpinPolicy for Spin {
+    fn backoff(&self, attempt: u32) -> bool {
+        if attempt >= self.limit {
+            return false;
+        }
+        // tells the processor that we're spinning,
+        // this hint will result in a special instruction that
+        // causes the processor core to optimizeits behavior
+        std::hint::spin_loop();
+        true
+    }
+}
+
+/// Spins twice as long after every attempt, but at most `2^max_shift` times,
+/// and gives up after `limit` attempts.
+///
+/// Backing off keeps waiters from hammering the cache line of the lock.
+pub struct ExponentialBackoff {
+    pub max_shift: u32,
+    pub limit: u32,
+}
+
+impl SpinPolicy for ExponentialBackoff {
+    fn backoff(&self, attempt: u32) -> bool {
+        if attempt >= self.limit {
+            return false;
+        }
+        for _ in 0..1u64 << attempt.min(self.max_shift).min(63) {
+            std::hint::spin_loop();
+        }
+        true
+    }
+}
+
+/// Spins for `spins` attempts, then yields to the scheduler for `yields` more.
+///
+/// Useful when there are more threads than cores, so the holder gets to run.
+pub struct YieldAfter {
+    pub spins: u32,
+    pub yields: u32,
+}
+
+impl SpinPolicy for YieldAfter {
+    fn backoff(&self, attempt: u32) -> bool {
+        if attempt < self.spins {
+            std::hint::spin_loop();
+        } else if attempt < self.spins.saturating_add(self.yields) {
+            thread::yield_now();
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+/// Doesn't spin at all, go to sleep right away.
+pub struct NoSpin;
+
+impl SpinPolicy for NoSpin {
+    fn backoff(&self, _attempt: u32) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::{ExponentialBackoff, NoSpin, Spin, SpinPolicy, YieldAfter};
+    use crate::{mutex::Mutex, read_write_lock::RwLock, spin::SpinLock};
+
+    #[test]
+    fn limits() {
+        let policy = YieldAfter {
+            spins: 2,
+            yields: 1,
+        };
+        let attempts = (0..).take_while(|&i| policy.backoff(i)).count();
+        assert_eq!(attempts, 3);
+        assert!(!NoSpin.backoff(0));
+        assert!(!Spin { limit: 0 }.backoff(0));
+    }
+
+    #[test]
+    fn policies() {
+        let policies: [&'static dyn SpinPolicy; 4] = [
+            &NoSpin,
+            &Spin { limit: 10 },
+            &ExponentialBackoff {
+                max_shift: 6,
+                limit: 10,
+            },
+            &YieldAfter {
+                spins: 10,
+                yields: 10,
+            },
+        ];
+        for policy in policies {
+            let mutex = Mutex::new(0).with_spin_policy(policy);
+            let rwlock = RwLock::new(0).with_spin_policy(policy);
+            let spin = SpinLock::new(0).with_spin_policy(policy);
+            thread::scope(|s| {
+                for _ in 0..4 {
+                    s.spawn(|| {
+                        for _ in 0..1000 {
+                            *mutex.lock().unwrap() += 1;
+                            *rwlock.write().unwrap() += 1;
+                            let _ = *rwlock.read().unwrap();
+                            *spin.lock() += 1;
+                        }
+                    });
+                }
+            });
+            assert_eq!(*mutex.lock().unwrap(), 4000);
+            assert_eq!(*rwlock.read().unwrap(), 4000);
+            assert_eq!(*spin.lock(), 4000);
+        }
+    }
+}
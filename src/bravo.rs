@@ -0,0 +1,308 @@
+//! A reader-writer lock for data that is read much more often than written,
+//! after BRAVO (Biased Locking for Reader-Writer Locks, Dice and Kogan, 2019).
+//!
+//! `RwLock::read()` makes every reader write to the same `state`, so its cache
+//! line bounces between all cores that read. Here, while the lock is biased
+//! towards readers, a reader instead claims a slot in a global table, hashed
+//! from its thread and the lock, so different readers mostly write to
+//! different cache lines. A writer takes the underlying `RawRwLock`, turns the
+//! bias off, and waits until no slot in the table refers to the lock anymore.
+//!
+//! That scan is expensive, so after a writer had to do it, the bias stays off
+//! for a while, proportional to how long the scan took. In the meantime, and
+//! when a reader's slot is taken, readers use the `RawRwLock` like `RwLock`
+//! does, so under writer pressure it behaves just like that.
+
+use std::{
+    cell::UnsafeCell,
+    hint,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{
+            AtomicBool, AtomicU64, AtomicUsize,
+            Ordering::{Acquire, Relaxed, Release, SeqCst},
+        },
+        OnceLock,
+    },
+    thread,
+    time::Instant,
+};
+
+use crate::{
+    lock_api::RawRwLock as _,
+    poison::{self, LockResult, PoisonError},
+    read_write_lock::RawRwLock,
+};
+
+/// The number of slots in the table, shared by all `BravoRwLock`s.
+const SLOTS: usize = 1024;
+
+/// How many times as long as the last scan the bias stays off.
+const INHIBIT_MULTIPLIER: u64 = 9;
+
+/// Holds the address of the lock a reader has read locked, or 0.
+/// On its own cache line, so readers in different slots don't interfere.
+#[repr(align(64))]
+struct Slot(AtomicUsize);
+
+static VISIBLE_READERS: [Slot; SLOTS] = [const { Slot(AtomicUsize::new(0)) }; SLOTS];
+
+pub struct BravoRwLock<T> {
+    /// Whether readers may use `VISIBLE_READERS` instead of `raw`.
+    rbias: AtomicBool,
+    /// When readers may turn `rbias` on again, in nanoseconds, see `now()`.
+    inhibit_until: AtomicU64,
+    raw: RawRwLock,
+    /// Only writers poison the lock, like `RwLock`.
+    poison: poison::Flag,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for BravoRwLock<T> {}
+
+pub struct ReadGuard<'a, T> {
+    lock: &'a BravoRwLock<T>,
+    /// The slot we claimed, or `None` if we have a read lock on `raw`.
+    slot: Option<&'static Slot>,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        match self.slot {
+            // Release matches the load in `revoke_bias()`,
+            // so the writer waits until we're done reading.
+            Some(slot) => slot.0.store(0, Release),
+            None => unsafe { self.lock.raw.unlock_shared() },
+        }
+    }
+}
+
+pub struct WriteGuard<'a, T> {
+    lock: &'a BravoRwLock<T>,
+    poison: poison::Guard,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+        unsafe { self.lock.raw.unlock_exclusive() }
+    }
+}
+
+impl<T> BravoRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            rbias: AtomicBool::new(true),
+            inhibit_until: AtomicU64::new(0),
+            raw: RawRwLock::new(),
+            poison: poison::Flag::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> LockResult<ReadGuard<'_, T>> {
+        let mut slot = None;
+        if self.rbias.load(Acquire) {
+            slot = self.claim_slot();
+        }
+        if slot.is_none() {
+            self.raw.lock_shared();
+            // No writer can be revoking the bias while we hold a read lock.
+            if !self.rbias.load(Relaxed) && now() >= self.inhibit_until.load(Relaxed) {
+                // Release, so fast readers that see this also see what the
+                // last writer wrote, which we saw by locking `raw`.
+                self.rbias.store(true, Release);
+            }
+        }
+        let guard = ReadGuard { lock: self, slot };
+        if self.poison.get() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> LockResult<WriteGuard<'_, T>> {
+        self.raw.lock_exclusive();
+        if self.rbias.load(Relaxed) {
+            self.revoke_bias();
+        }
+        poison::map_result(self.poison.guard(), |poison| WriteGuard {
+            lock: self,
+            poison,
+        })
+    }
+
+    /// Whether a thread panicked while holding the write lock.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Call this after the data has been brought back into a consistent state.
+    pub fn clear_poison(&self) {
+        self.poison.clear()
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let value = self.value.get_mut();
+        if self.poison.get() {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        let value = self.value.into_inner();
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Returns the slot we claimed for a read lock, or `None` if it's taken
+    /// or the bias was revoked in the meantime.
+    fn claim_slot(&self) -> Option<&'static Slot> {
+        let slot = &VISIBLE_READERS[slot_index(self.id())];
+        // SeqCst, together with `revoke_bias()`: either the writer sees
+        // our slot, or we see that the bias is gone.
+        if slot
+            .0
+            .compare_exchange(0, self.id(), SeqCst, Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+        if self.rbias.load(SeqCst) {
+            return Some(slot);
+        }
+        slot.0.store(0, Relaxed);
+        None
+    }
+
+    /// Must only be called while holding `raw` exclusively.
+    fn revoke_bias(&self) {
+        self.rbias.store(false, SeqCst);
+        let start = now();
+        for slot in &VISIBLE_READERS {
+            let mut attempt = 0u32;
+            // Also Acquire, to see everything the readers did before leaving their slots.
+            while slot.0.load(SeqCst) == self.id() {
+                if attempt < 100 {
+                    hint::spin_loop();
+                } else {
+                    thread::yield_now();
+                }
+                attempt = attempt.saturating_add(1);
+            }
+        }
+        let end = now();
+        self.inhibit_until.store(
+            end.saturating_add((end - start).saturating_mul(INHIBIT_MULTIPLIER)),
+            Relaxed,
+        );
+    }
+}
+
+/// The slot for the current thread and the lock at `id`.
+fn slot_index(id: usize) -> usize {
+    static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static THREAD: usize = NEXT_THREAD.fetch_add(1, Relaxed);
+    }
+    let thread = THREAD.with(|t| *t);
+    // Fibonacci hashing, so consecutive threads and locks spread out.
+    let hash = (thread ^ (id >> 6)).wrapping_mul(0x9e37_79b9_7f4a_7c15_u64 as usize);
+    hash >> (usize::BITS - SLOTS.trailing_zeros())
+}
+
+/// Nanoseconds since the first call.
+fn now() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering::Relaxed},
+        thread,
+    };
+
+    use super::BravoRwLock;
+
+    #[test]
+    fn bias() {
+        let lock = BravoRwLock::new(0);
+        let r = lock.read().unwrap();
+        assert!(r.slot.is_some());
+        // Same thread and lock, so the same slot, which is taken.
+        let r2 = lock.read().unwrap();
+        assert!(r2.slot.is_none());
+        drop((r, r2));
+
+        *lock.write().unwrap() += 1;
+        assert!(!lock.rbias.load(Relaxed));
+        // Readers use the lock itself until the inhibit period is over,
+        // then one of them turns the bias back on.
+        while lock.read().unwrap().slot.is_none() {
+            thread::yield_now();
+        }
+        assert_eq!(*lock.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn readers_and_writers() {
+        let lock = BravoRwLock::new((0, 0));
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(Relaxed) {
+                        let g = lock.read().unwrap();
+                        assert_eq!(g.0, g.1);
+                        drop(g);
+                        // Don't hog the CPU if there are fewer cores than threads.
+                        thread::yield_now();
+                    }
+                });
+            }
+            for _ in 0..1000 {
+                let mut g = lock.write().unwrap();
+                g.0 += 1;
+                thread::yield_now();
+                g.1 += 1;
+            }
+            done.store(true, Relaxed);
+        });
+        assert_eq!(lock.into_inner().unwrap(), (1000, 1000));
+    }
+}
@@ -1,56 +1,276 @@
-use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering::Relaxed};
+use std::{
+    ops::DerefMut,
+    ptr,
+    sync::atomic::{
+        AtomicPtr, AtomicU32, AtomicUsize,
+        Ordering::{Relaxed, SeqCst},
+    },
+    time::{Duration, Instant},
+};
 
 use atomic_wait::{wake_all, wake_one};
 
-use crate::mutex::MutexGuard;
+use crate::{
+    futex,
+    lock_api::RawMutex as _,
+    mutex::RawMutex,
+    poison::{self, LockResult, PoisonError},
+    stats::{LockStats, Timer},
+};
+
+/// A lock guard that a `Condvar` can wait with: it can let go of its lock
+/// for a while, and take it back in the same mode.
+///
+/// Mapped guards work too. The reference they hold stays valid while
+/// they're unlocked, since the lock (and so the data) is still borrowed.
+///
+/// # Safety
+///
+/// `raw_mutex()` must only return a mutex that this guard holds locked,
+/// since `Condvar` unlocks and relocks it on the guard's behalf.
+pub unsafe trait CondvarGuard {
+    /// Unlocks, calls `f`, and locks again, even if `f` panics.
+    fn unlocked<R>(&mut self, f: impl FnOnce() -> R) -> R;
+
+    /// Whether the lock is poisoned, checked after locking again.
+    ///
+    /// Guards that don't know about poisoning, like those of `lock_api`
+    /// (and so `read_write_lock::ReadGuard`), always say no.
+    fn is_poisoned(&self) -> bool {
+        false
+    }
+
+    /// The futex mutex behind this guard, if it is one.
+    ///
+    /// `Condvar` then unlocks and relocks it itself instead of using `unlocked()`,
+    /// which lets `notify_all()` move its waiters straight onto the mutex.
+    fn raw_mutex(&self) -> Option<&RawMutex> {
+        None
+    }
+}
+
+/// Calls `f` between `unlock` and `lock`, and still calls `lock` if `f` panics,
+/// since the guard will unlock once more when it's dropped.
+pub(crate) fn with_unlocked<R>(
+    unlock: impl FnOnce(),
+    lock: impl FnOnce(),
+    f: impl FnOnce() -> R,
+) -> R {
+    struct Relock<L: FnOnce()>(Option<L>);
+    impl<L: FnOnce()> Drop for Relock<L> {
+        fn drop(&mut self) {
+            if let Some(lock) = self.0.take() {
+                lock();
+            }
+        }
+    }
+
+    unlock();
+    let _relock = Relock(Some(lock));
+    f()
+}
+
+/// Whether a timed wait on a `Condvar` gave up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
 
 pub struct Condvar {
     counter: AtomicU32,
     num_waiters: AtomicUsize,
+    /// The futex of the `RawMutex` the waiters use, null before the first wait,
+    /// or `SEVERAL` once waiters used another lock.
+    mutex: AtomicPtr<AtomicU32>,
+    stats: LockStats,
 }
 
+/// Never the address of a real futex.
+const SEVERAL: *mut AtomicU32 = ptr::NonNull::dangling().as_ptr();
+
 impl Condvar {
     pub const fn new() -> Self {
         Self {
             counter: AtomicU32::new(0),
             num_waiters: AtomicUsize::new(0),
+            mutex: AtomicPtr::new(ptr::null_mut()),
+            stats: LockStats::new(),
         }
     }
 
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::StatsSnapshot {
+        self.stats.snapshot()
+    }
+
     pub fn notify_one(&self) {
         if self.num_waiters.load(Relaxed) > 0 {
             self.counter.fetch_add(1, Relaxed);
+            self.stats.futex_wake();
             wake_one(&self.counter);
         }
     }
 
+    /// Wakes all waiters, but only one at a time if they wait with a `mutex::Mutex`:
+    /// all but one go straight from waiting on the condvar to waiting on the mutex,
+    /// instead of all waking up just to block on the mutex again.
     pub fn notify_all(&self) {
-        if self.num_waiters.load(Relaxed) > 0 {
-            self.counter.fetch_add(1, Relaxed);
+        if self.num_waiters.load(Relaxed) == 0 {
+            return;
+        }
+        // SeqCst, together with the one in `set_mutex()`: a waiter that missed this
+        // increment must have set the mutex before, so we can't requeue it onto the wrong one.
+        self.counter.fetch_add(1, SeqCst);
+        self.stats.futex_wake();
+        let mutex = self.mutex.load(SeqCst);
+        if mutex.is_null() || mutex == SEVERAL {
             wake_all(&self.counter);
+            return;
+        }
+        // The requeued threads don't set the mutex state to 2 for themselves.
+        // The one we wake does, since it relocks with `lock_requeued()`,
+        // and so does each one after it, so every unlock wakes the next.
+        //
+        // For a private futex, the kernel only uses the address of `mutex`,
+        // it never reads it, so this is fine even if all waiters (and the mutex) are gone.
+        while !futex::requeue(&self.counter, self.counter.load(Relaxed), mutex) {
+            // Another notification came in between, try again with its value.
         }
     }
 
-    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    /// Unlocks the guard's lock until notified, then locks it again.
+    ///
+    /// Returns a `PoisonError` if the lock was poisoned while we were waiting.
+    pub fn wait<G: CondvarGuard>(&self, guard: G) -> LockResult<G> {
+        poison::map_result(self.wait_deadline(guard, None), |(guard, _)| guard)
+    }
+
+    /// Waits until `condition` returns `false`, and returns the guard
+    /// that it last returned `false` for.
+    pub fn wait_while<G: CondvarGuard + DerefMut>(
+        &self,
+        mut guard: G,
+        mut condition: impl FnMut(&mut G::Target) -> bool,
+    ) -> LockResult<G> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    /// Like `wait()`, but gives up after `timeout`.
+    ///
+    /// Like `wait()`, it can also return early without a notification,
+    /// which isn't reported as a timeout.
+    pub fn wait_timeout<G: CondvarGuard>(
+        &self,
+        guard: G,
+        timeout: Duration,
+    ) -> LockResult<(G, WaitTimeoutResult)> {
+        // Too far in the future to be representable, just wait forever.
+        self.wait_deadline(guard, Instant::now().checked_add(timeout))
+    }
+
+    /// Like `wait()`, but gives up once `deadline` has passed.
+    pub fn wait_until<G: CondvarGuard>(
+        &self,
+        guard: G,
+        deadline: Instant,
+    ) -> LockResult<(G, WaitTimeoutResult)> {
+        self.wait_deadline(guard, Some(deadline))
+    }
+
+    /// Like `wait_while()`, but gives up after `timeout`.
+    ///
+    /// The result only says it timed out if `condition` still returned `true` in the end.
+    pub fn wait_timeout_while<G: CondvarGuard + DerefMut>(
+        &self,
+        mut guard: G,
+        timeout: Duration,
+        mut condition: impl FnMut(&mut G::Target) -> bool,
+    ) -> LockResult<(G, WaitTimeoutResult)> {
+        let deadline = Instant::now().checked_add(timeout);
+        while condition(&mut *guard) {
+            let timed_out;
+            (guard, timed_out) = self.wait_deadline(guard, deadline)?;
+            if timed_out.timed_out() {
+                let timed_out = condition(&mut *guard);
+                return Ok((guard, WaitTimeoutResult(timed_out)));
+            }
+        }
+        Ok((guard, WaitTimeoutResult(false)))
+    }
+
+    /// Remembers which mutex the waiters use, or that there's more than one.
+    fn set_mutex(&self, mutex: *mut AtomicU32) {
+        if let Err(old) = self
+            .mutex
+            .compare_exchange(ptr::null_mut(), mutex, SeqCst, SeqCst)
+        {
+            if old != mutex {
+                self.mutex.store(SEVERAL, SeqCst);
+            }
+        }
+    }
+
+    fn wait_deadline<G: CondvarGuard>(
+        &self,
+        mut guard: G,
+        deadline: Option<Instant>,
+    ) -> LockResult<(G, WaitTimeoutResult)> {
         self.num_waiters.fetch_add(1, Relaxed);
 
         let counter_value = self.counter.load(Relaxed);
 
-        let mutex = guard.mutex;
-        drop(guard);
-
-        atomic_wait::wait(&self.counter, counter_value);
+        let sleep = || {
+            let timer = Timer::start();
+            self.stats.futex_wait();
+            let timed_out = !futex::wait_until(&self.counter, counter_value, deadline);
+            self.stats.waited(timer);
+            timed_out
+        };
+        let timed_out = match guard.raw_mutex() {
+            Some(raw) => {
+                self.set_mutex(raw.futex().as_ptr().cast());
+                // Safety: `CondvarGuard` promises the guard holds this mutex
+                // locked, and the guard isn't used while it's unlocked.
+                unsafe { raw.unlock() };
+                let timed_out = sleep();
+                raw.lock_requeued();
+                timed_out
+            }
+            None => {
+                self.set_mutex(SEVERAL);
+                guard.unlocked(sleep)
+            }
+        };
 
         self.num_waiters.fetch_sub(1, Relaxed);
-        mutex.lock()
+        let result = (guard, WaitTimeoutResult(timed_out));
+        if result.0.is_poisoned() {
+            Err(PoisonError::new(result))
+        } else {
+            Ok(result)
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::{assert_eq, thread, time::Duration};
+    use std::{
+        assert_eq, thread,
+        time::{Duration, Instant},
+    };
 
-    use crate::mutex::Mutex;
+    use crate::{
+        mutex::{Mutex, MutexGuard},
+        read_write_lock::RwLock,
+        spin::SpinLock,
+    };
 
     use super::Condvar;
 
@@ -64,13 +284,13 @@ mod test {
         thread::scope(|s| {
             s.spawn(|| {
                 thread::sleep(Duration::from_secs(1));
-                *mutex.lock() = 123;
+                *mutex.lock().unwrap() = 123;
                 condvar.notify_one();
             });
 
-            let mut m = mutex.lock();
+            let mut m = mutex.lock().unwrap();
             while *m < 100 {
-                m = condvar.wait(m);
+                m = condvar.wait(m).unwrap();
                 wakeups += 1;
             }
 
@@ -80,4 +300,133 @@ mod test {
         // Check we don't spinning, but allow some spurious wake ups.
         assert!(wakeups < 10);
     }
+
+    #[test]
+    fn condvar_mapped() {
+        let mutex = Mutex::new((0, "unrelated"));
+        let condvar = Condvar::new();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                mutex.lock().unwrap().0 = 123;
+                condvar.notify_one();
+            });
+
+            let mut m = MutexGuard::map(mutex.lock().unwrap(), |(n, _)| n);
+            while *m < 100 {
+                m = condvar.wait(m).unwrap();
+            }
+            assert_eq!(*m, 123);
+        });
+    }
+
+    #[test]
+    fn timeouts() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+
+        let start = Instant::now();
+        let (g, r) = condvar
+            .wait_timeout(mutex.lock().unwrap(), Duration::from_millis(50))
+            .unwrap();
+        assert!(r.timed_out());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let (g, r) = condvar.wait_until(g, Instant::now()).unwrap();
+        assert!(r.timed_out());
+        drop(g);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..3 {
+                    thread::sleep(Duration::from_millis(20));
+                    *mutex.lock().unwrap() += 1;
+                    condvar.notify_all();
+                }
+            });
+
+            let g = condvar
+                .wait_while(mutex.lock().unwrap(), |n| *n < 2)
+                .unwrap();
+            assert!(*g >= 2);
+            drop(g);
+
+            let (g, r) = condvar
+                .wait_timeout_while(mutex.lock().unwrap(), Duration::from_secs(10), |n| *n < 3)
+                .unwrap();
+            assert!(!r.timed_out());
+            assert_eq!(*g, 3);
+            drop(g);
+
+            // Nobody will make it 4.
+            let (g, r) = condvar
+                .wait_timeout_while(mutex.lock().unwrap(), Duration::from_millis(50), |n| *n < 4)
+                .unwrap();
+            assert!(r.timed_out());
+            assert_eq!(*g, 3);
+        });
+    }
+
+    #[test]
+    fn other_guards() {
+        let rwlock = RwLock::new(0);
+        let spin = SpinLock::new(0);
+        let condvar = Condvar::new();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                *rwlock.write().unwrap() = 1;
+                *spin.lock() = 1;
+                condvar.notify_all();
+            });
+
+            let g = condvar
+                .wait_while(rwlock.write().unwrap(), |n| *n == 0)
+                .unwrap();
+            drop(g);
+            let g = condvar.wait_while(spin.lock(), |n| *n == 0).unwrap();
+            assert_eq!(*g, 1);
+        });
+
+        // Readers can wait too, but only with their own loop.
+        let mut g = rwlock.read().unwrap();
+        while *g == 0 {
+            g = condvar.wait(g).unwrap();
+        }
+        let (g, r) = condvar.wait_timeout(g, Duration::from_millis(10)).unwrap();
+        assert!(r.timed_out());
+        assert_eq!(*g, 1);
+    }
+
+    #[test]
+    fn notify_all_requeue() {
+        let mutex = Mutex::new((false, 0));
+        let condvar = Condvar::new();
+        let several = Condvar::new();
+        // Once another lock was used, it's back to waking everyone.
+        let spin = SpinLock::new(());
+        drop(several.wait_timeout(spin.lock(), Duration::ZERO));
+
+        for condvar in [&condvar, &several] {
+            thread::scope(|s| {
+                for _ in 0..8 {
+                    s.spawn(|| {
+                        let mut g = condvar.wait_while(mutex.lock().unwrap(), |(go, _)| !*go);
+                        g.as_mut().unwrap().1 += 1;
+                    });
+                }
+                thread::sleep(Duration::from_millis(100));
+                let mut g = mutex.lock().unwrap();
+                g.0 = true;
+                // Everyone but one is moved to the mutex, which we're still holding.
+                condvar.notify_all();
+                thread::sleep(Duration::from_millis(10));
+                drop(g);
+            });
+            let mut g = mutex.lock().unwrap();
+            assert_eq!(*g, (true, 8));
+            *g = (false, 0);
+        }
+    }
 }
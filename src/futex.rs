@@ -0,0 +1,135 @@
+//! Raw futex operations that `atomic_wait` doesn't provide.
+
+use std::{
+    io, ptr,
+    sync::atomic::AtomicU32,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Blocks while `*a == expected`, but no longer than until `deadline`.
+///
+/// Returns `false` if the deadline has passed, `true` otherwise.
+/// Like `atomic_wait::wait`, it may also return spuriously.
+pub(crate) fn wait_until(a: &AtomicU32, expected: u32, deadline: Option<Instant>) -> bool {
+    let timeout = match deadline {
+        None => None,
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(d) if !d.is_zero() => Some(timespec(d)),
+            _ => return false,
+        },
+    };
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            timeout.as_ref().map_or(ptr::null(), |t| t as *const _),
+        )
+    };
+    !(r == -1 && errno() == libc::ETIMEDOUT)
+}
+
+/// Wakes up to `n` threads waiting on `a`, returning how many were woken.
+pub(crate) fn wake(a: &AtomicU32, n: i32) -> usize {
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            n,
+        )
+    };
+    r.max(0) as usize
+}
+
+/// Wakes one thread waiting on `a`, and moves all other waiters over to `to`,
+/// as if they had been waiting there.
+///
+/// Returns `false` without doing anything if `*a != expected`.
+pub(crate) fn requeue(a: &AtomicU32, expected: u32, to: *const AtomicU32) -> bool {
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_CMP_REQUEUE | libc::FUTEX_PRIVATE_FLAG,
+            1,
+            // The number of threads to requeue, passed in place of the timeout.
+            i32::MAX as usize,
+            to,
+            expected,
+        )
+    };
+    !(r == -1 && errno() == libc::EAGAIN)
+}
+
+/// Blocks until the kernel made us the owner of the PI futex `a`,
+/// boosting the priority of the current owner while we wait.
+///
+/// Returns `Ok(false)` if `deadline` passed first.
+/// The kernel measures this timeout against the realtime clock,
+/// so a clock change while waiting moves the deadline.
+///
+/// Fails if the kernel won't wait, for example with `ESRCH` when the owner
+/// in the lock word is a thread that exited without unlocking.
+pub(crate) fn lock_pi(a: &AtomicU32, deadline: Option<Instant>) -> io::Result<bool> {
+    let timeout = match deadline {
+        None => None,
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(d) if !d.is_zero() => {
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                Some(timespec(now.saturating_add(d)))
+            }
+            _ => return Ok(false),
+        },
+    };
+    loop {
+        let r = unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                a as *const AtomicU32,
+                libc::FUTEX_LOCK_PI | libc::FUTEX_PRIVATE_FLAG,
+                0,
+                timeout.as_ref().map_or(ptr::null(), |t| t as *const _),
+            )
+        };
+        if r == 0 {
+            return Ok(true);
+        }
+        match errno() {
+            libc::ETIMEDOUT => return Ok(false),
+            libc::EINTR | libc::EAGAIN => continue,
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+/// Releases the PI futex `a`, which must be owned by the current thread,
+/// and hands it to the highest priority waiter.
+pub(crate) fn unlock_pi(a: &AtomicU32) {
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_UNLOCK_PI | libc::FUTEX_PRIVATE_FLAG,
+        )
+    };
+    assert!(
+        r == 0,
+        "FUTEX_UNLOCK_PI failed: {}",
+        std::io::Error::last_os_error()
+    );
+}
+
+fn timespec(d: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: d.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: d.subsec_nanos() as _,
+    }
+}
+
+fn errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
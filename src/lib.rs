@@ -1,8 +1,23 @@
 pub mod arc;
+pub mod arc_swap;
+pub mod async_mutex;
+pub mod backoff;
+pub mod bravo;
 pub mod channel;
 pub mod condition_variable;
+mod futex;
+pub mod lock_api;
+pub mod lockdep;
+pub mod membarrier;
 pub mod mutex;
+pub mod pi_mutex;
+pub mod poison;
+pub mod queue_lock;
 pub mod read_write_lock;
+pub mod reentrant_mutex;
+pub mod robust_mutex;
+pub mod seq_lock;
 pub mod spin;
 pub mod state_machine_channel;
+pub mod stats;
 pub mod type_safe_channel;
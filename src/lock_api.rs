@@ -0,0 +1,1116 @@
+//! Lock algorithms split from the data they protect, like the `lock_api` crate.
+//!
+//! A lock algorithm only implements `RawMutex` or `RawRwLock` on its lock word,
+//! and `Mutex<R, T>` and `RwLock<R, T>` add the `UnsafeCell`, the guards,
+//! `map()` and the rest on top of it.
+//!
+//! `SpinLock` is `Mutex<RawSpinLock, T>`. The std-like `mutex::Mutex` and
+//! `read_write_lock::RwLock` wrap `Mutex<RawMutex, T>` and `RwLock<RawRwLock, T>`
+//! to add poisoning.
+
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    time::{Duration, Instant},
+};
+
+use crate::condition_variable::{with_unlocked, CondvarGuard};
+
+/// A lock that only one thread can hold at a time.
+///
+/// # Safety
+///
+/// `lock()` and a successful `try_lock()` must not return while another
+/// thread holds the lock, and must synchronize with the previous `unlock()`.
+pub unsafe trait RawMutex {
+    /// An unlocked lock, so `Mutex::new()` can be `const`.
+    const INIT: Self;
+
+    /// What `Mutex<Self, T>` calls itself in its `Debug` output.
+    const NAME: &'static str = "Mutex";
+
+    /// `GuardSend`, or `GuardNoSend` if the lock must be unlocked
+    /// on the thread that locked it.
+    type GuardMarker;
+
+    #[track_caller]
+    fn lock(&self);
+
+    #[track_caller]
+    fn try_lock(&self) -> bool;
+
+    /// # Safety
+    ///
+    /// The lock must be held by the current context.
+    unsafe fn unlock(&self);
+
+    /// Like `try_lock()`, but not counted in the stats or tracked by lockdep,
+    /// so that `Debug` can look at the data without it counting as a use of the lock.
+    fn try_lock_quiet(&self) -> bool {
+        self.try_lock()
+    }
+
+    /// Unlocks a lock taken by `try_lock_quiet()`.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the current context, through `try_lock_quiet()`.
+    unsafe fn unlock_quiet(&self) {
+        self.unlock()
+    }
+}
+
+/// A `RawMutex` that can hand the lock directly to a waiting thread.
+///
+/// # Safety
+///
+/// Same as `RawMutex`.
+pub unsafe trait RawMutexFair: RawMutex {
+    /// Like `unlock()`, but the lock isn't up for grabs if a thread is waiting.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the current context.
+    unsafe fn unlock_fair(&self);
+}
+
+/// A `RawMutex` that can give up waiting.
+///
+/// # Safety
+///
+/// Same as `RawMutex`.
+pub unsafe trait RawMutexTimed: RawMutex {
+    /// Returns `false` if `deadline` passed before we got the lock.
+    #[track_caller]
+    fn try_lock_until(&self, deadline: Instant) -> bool;
+}
+
+/// A lock that is held either by any number of readers or by one writer.
+///
+/// # Safety
+///
+/// An exclusive lock must not be held at the same time as any other lock,
+/// and locking must synchronize with the previous exclusive unlock.
+pub unsafe trait RawRwLock {
+    /// An unlocked lock, so `RwLock::new()` can be `const`.
+    const INIT: Self;
+
+    /// `GuardSend`, or `GuardNoSend` if a lock must be unlocked
+    /// on the thread that locked it.
+    type GuardMarker;
+
+    #[track_caller]
+    fn lock_shared(&self);
+
+    #[track_caller]
+    fn try_lock_shared(&self) -> bool;
+
+    /// # Safety
+    ///
+    /// A shared lock must be held by the current context.
+    unsafe fn unlock_shared(&self);
+
+    #[track_caller]
+    fn lock_exclusive(&self);
+
+    #[track_caller]
+    fn try_lock_exclusive(&self) -> bool;
+
+    /// # Safety
+    ///
+    /// The exclusive lock must be held by the current context.
+    unsafe fn unlock_exclusive(&self);
+}
+
+/// A `GuardMarker` for guards that may be sent to another thread.
+pub struct GuardSend(());
+
+/// A `GuardMarker` for guards that must stay on their thread.
+pub struct GuardNoSend(PhantomData<*mut ()>);
+
+unsafe impl Sync for GuardNoSend {}
+
+/// A `RawRwLock` that can give up waiting.
+///
+/// # Safety
+///
+/// Same as `RawRwLock`.
+pub unsafe trait RawRwLockTimed: RawRwLock {
+    /// Returns `false` if `deadline` passed before we got a shared lock.
+    #[track_caller]
+    fn try_lock_shared_until(&self, deadline: Instant) -> bool;
+
+    /// Returns `false` if `deadline` passed before we got the exclusive lock.
+    #[track_caller]
+    fn try_lock_exclusive_until(&self, deadline: Instant) -> bool;
+}
+
+/// A `RawRwLock` with an upgradable lock: a shared lock that only one thread
+/// can hold at a time, and that can be turned into the exclusive lock
+/// without letting another writer in between.
+///
+/// # Safety
+///
+/// An upgradable lock must not be held at the same time as an exclusive or
+/// another upgradable lock, and `upgrade()` must wait until all readers are gone.
+pub unsafe trait RawRwLockUpgrade: RawRwLock {
+    #[track_caller]
+    fn lock_upgradable(&self);
+
+    #[track_caller]
+    fn try_lock_upgradable(&self) -> bool;
+
+    /// # Safety
+    ///
+    /// An upgradable lock must be held by the current context.
+    unsafe fn unlock_upgradable(&self);
+
+    /// Turns the upgradable lock into the exclusive lock.
+    ///
+    /// # Safety
+    ///
+    /// An upgradable lock must be held by the current context.
+    unsafe fn upgrade(&self);
+
+    /// Like `upgrade()`, but keeps the upgradable lock and returns `false`
+    /// instead of waiting for readers.
+    ///
+    /// # Safety
+    ///
+    /// An upgradable lock must be held by the current context.
+    unsafe fn try_upgrade(&self) -> bool;
+}
+
+/// A `RawRwLock` whose exclusive lock can be turned into a shared lock.
+///
+/// # Safety
+///
+/// `downgrade()` must not let a writer in between.
+pub unsafe trait RawRwLockDowngrade: RawRwLock {
+    /// Turns the exclusive lock into a shared lock.
+    ///
+    /// # Safety
+    ///
+    /// The exclusive lock must be held by the current context.
+    unsafe fn downgrade(&self);
+}
+
+pub struct Mutex<R, T> {
+    pub(crate) raw: R,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<R: RawMutex + Sync, T: Send> Sync for Mutex<R, T> {}
+
+impl<R: RawMutex, T> Mutex<R, T> {
+    pub const fn new(value: T) -> Self {
+        Self::from_raw(R::INIT, value)
+    }
+
+    /// For a raw lock that was configured, rather than just `R::INIT`.
+    pub const fn from_raw(raw: R, value: T) -> Self {
+        Self {
+            raw,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, R, T> {
+        self.raw.lock();
+        MutexGuard {
+            mutex: self,
+            _marker: PhantomData,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, R, T>> {
+        if self.raw.try_lock() {
+            Some(MutexGuard {
+                mutex: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// The lock itself, for example to lock it without a guard.
+    ///
+    /// # Safety
+    ///
+    /// Unlocking it while a guard exists is undefined behaviour.
+    pub unsafe fn raw(&self) -> &R {
+        &self.raw
+    }
+}
+
+impl<R: RawMutexTimed, T> Mutex<R, T> {
+    /// Like `lock()`, but gives up and returns `None` after `timeout`.
+    #[track_caller]
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<MutexGuard<'_, R, T>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            // Too far in the future to be representable, just wait forever.
+            None => Some(self.lock()),
+        }
+    }
+
+    /// Like `lock()`, but gives up and returns `None` once `deadline` has passed.
+    #[track_caller]
+    pub fn try_lock_until(&self, deadline: Instant) -> Option<MutexGuard<'_, R, T>> {
+        if self.raw.try_lock_until(deadline) {
+            Some(MutexGuard {
+                mutex: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl<R: RawMutex, T: Default> Default for Mutex<R, T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<R: RawMutex, T: fmt::Debug> fmt::Debug for Mutex<R, T> {
+    /// Like `std::sync::Mutex`, only shows the data if it isn't locked.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        /// Unlocks even if `T`'s `Debug` panics, like a guard would.
+        struct Unlock<'a, R: RawMutex>(&'a R);
+
+        impl<R: RawMutex> Drop for Unlock<'_, R> {
+            fn drop(&mut self) {
+                unsafe { self.0.unlock_quiet() }
+            }
+        }
+
+        let mut d = f.debug_struct(R::NAME);
+        if self.raw.try_lock_quiet() {
+            let _unlock = Unlock(&self.raw);
+            // Safety: We just locked it.
+            d.field("data", &unsafe { &*self.value.get() });
+        } else {
+            d.field("data", &format_args!("<locked>"));
+        }
+        d.finish_non_exhaustive()
+    }
+}
+
+pub struct MutexGuard<'a, R: RawMutex, T> {
+    mutex: &'a Mutex<R, T>,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+impl<R: RawMutex, T> Deref for MutexGuard<'_, R, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<R: RawMutex, T> DerefMut for MutexGuard<'_, R, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<R: RawMutex, T> Drop for MutexGuard<'_, R, T> {
+    fn drop(&mut self) {
+        unsafe { self.mutex.raw.unlock() }
+    }
+}
+
+impl<R: RawMutex, T: fmt::Debug> fmt::Debug for MutexGuard<'_, R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, R: RawMutex, T> MutexGuard<'a, R, T> {
+    /// The mutex this guard locks.
+    ///
+    /// This is an associated function so it can't shadow a method of `T`,
+    /// like `map()`.
+    pub fn mutex(s: &Self) -> &'a Mutex<R, T> {
+        s.mutex
+    }
+
+    /// Narrows the guard down to a part of the data, like one field,
+    /// while keeping the mutex locked.
+    pub fn map<U: ?Sized>(
+        orig: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedMutexGuard<'a, R, U> {
+        // `orig` stays alive while `f` runs, so a panic in `f` unlocks.
+        let value = NonNull::from(f(unsafe { &mut *orig.mutex.value.get() }));
+        let orig = ManuallyDrop::new(orig);
+        MappedMutexGuard {
+            raw: &orig.mutex.raw,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Like `map()`, but gives the original guard back if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(
+        orig: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedMutexGuard<'a, R, U>, Self> {
+        match f(unsafe { &mut *orig.mutex.value.get() }) {
+            Some(value) => {
+                let value = NonNull::from(value);
+                let orig = ManuallyDrop::new(orig);
+                Ok(MappedMutexGuard {
+                    raw: &orig.mutex.raw,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(orig),
+        }
+    }
+}
+
+impl<R: RawMutexFair, T> MutexGuard<'_, R, T> {
+    /// Unlocks the mutex and hands it directly to a waiting thread, if any,
+    /// so this thread can't take it right back before the woken thread runs.
+    pub fn unlock_fair(self) {
+        let guard = ManuallyDrop::new(self);
+        unsafe { guard.mutex.raw.unlock_fair() }
+    }
+}
+
+/// A `MutexGuard` made by `MutexGuard::map()`, pointing to a part of the data.
+pub struct MappedMutexGuard<'a, R: RawMutex, T: ?Sized> {
+    pub(crate) raw: &'a R,
+    value: NonNull<T>,
+    _marker: PhantomData<(&'a mut T, R::GuardMarker)>,
+}
+
+unsafe impl<R: RawMutex + Sync, T: ?Sized + Sync> Sync for MappedMutexGuard<'_, R, T> {}
+
+impl<R: RawMutex, T: ?Sized> Deref for MappedMutexGuard<'_, R, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<R: RawMutex, T: ?Sized> DerefMut for MappedMutexGuard<'_, R, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<R: RawMutex, T: ?Sized> Drop for MappedMutexGuard<'_, R, T> {
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock() }
+    }
+}
+
+impl<'a, R: RawMutex, T: ?Sized> MappedMutexGuard<'a, R, T> {
+    /// Narrows the guard down further, see `MutexGuard::map()`.
+    pub fn map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedMutexGuard<'a, R, U> {
+        let value = NonNull::from(f(unsafe { orig.value.as_mut() }));
+        let orig = ManuallyDrop::new(orig);
+        MappedMutexGuard {
+            raw: orig.raw,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Like `map()`, but gives the original guard back if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedMutexGuard<'a, R, U>, Self> {
+        match f(unsafe { orig.value.as_mut() }) {
+            Some(value) => {
+                let value = NonNull::from(value);
+                let orig = ManuallyDrop::new(orig);
+                Ok(MappedMutexGuard {
+                    raw: orig.raw,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(orig),
+        }
+    }
+}
+
+impl<R: RawMutexFair, T: ?Sized> MappedMutexGuard<'_, R, T> {
+    /// See `MutexGuard::unlock_fair()`.
+    pub fn unlock_fair(self) {
+        let guard = ManuallyDrop::new(self);
+        unsafe { guard.raw.unlock_fair() }
+    }
+}
+
+pub struct RwLock<R, T> {
+    pub(crate) raw: R,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<R: RawRwLock + Sync, T: Send + Sync> Sync for RwLock<R, T> {}
+
+impl<R: RawRwLock, T> RwLock<R, T> {
+    pub const fn new(value: T) -> Self {
+        Self::from_raw(R::INIT, value)
+    }
+
+    /// For a raw lock that was configured, rather than just `R::INIT`.
+    pub const fn from_raw(raw: R, value: T) -> Self {
+        Self {
+            raw,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> ReadGuard<'_, R, T> {
+        self.raw.lock_shared();
+        ReadGuard {
+            rwlock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_read(&self) -> Option<ReadGuard<'_, R, T>> {
+        if self.raw.try_lock_shared() {
+            Some(ReadGuard {
+                rwlock: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> WriteGuard<'_, R, T> {
+        self.raw.lock_exclusive();
+        WriteGuard {
+            rwlock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<WriteGuard<'_, R, T>> {
+        if self.raw.try_lock_exclusive() {
+            Some(WriteGuard {
+                rwlock: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// The lock itself, for example to lock it without a guard.
+    ///
+    /// # Safety
+    ///
+    /// Unlocking it while a guard exists is undefined behaviour.
+    pub unsafe fn raw(&self) -> &R {
+        &self.raw
+    }
+}
+
+impl<R: RawRwLockTimed, T> RwLock<R, T> {
+    /// Like `read()`, but gives up and returns `None` after `timeout`.
+    #[track_caller]
+    pub fn try_read_for(&self, timeout: Duration) -> Option<ReadGuard<'_, R, T>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_read_until(deadline),
+            // Too far in the future to be representable, just wait forever.
+            None => Some(self.read()),
+        }
+    }
+
+    /// Like `read()`, but gives up and returns `None` once `deadline` has passed.
+    #[track_caller]
+    pub fn try_read_until(&self, deadline: Instant) -> Option<ReadGuard<'_, R, T>> {
+        if self.raw.try_lock_shared_until(deadline) {
+            Some(ReadGuard {
+                rwlock: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Like `write()`, but gives up and returns `None` after `timeout`.
+    #[track_caller]
+    pub fn try_write_for(&self, timeout: Duration) -> Option<WriteGuard<'_, R, T>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_write_until(deadline),
+            None => Some(self.write()),
+        }
+    }
+
+    /// Like `write()`, but gives up and returns `None` once `deadline` has passed.
+    #[track_caller]
+    pub fn try_write_until(&self, deadline: Instant) -> Option<WriteGuard<'_, R, T>> {
+        if self.raw.try_lock_exclusive_until(deadline) {
+            Some(WriteGuard {
+                rwlock: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl<R: RawRwLockUpgrade, T> RwLock<R, T> {
+    /// A read lock that can later be upgraded to a write lock,
+    /// see `UpgradableReadGuard::upgrade()`.
+    ///
+    /// Other readers can still come in, but only one thread
+    /// can have an upgradable read lock at a time.
+    #[track_caller]
+    pub fn upgradable_read(&self) -> UpgradableReadGuard<'_, R, T> {
+        self.raw.lock_upgradable();
+        UpgradableReadGuard {
+            rwlock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_upgradable_read(&self) -> Option<UpgradableReadGuard<'_, R, T>> {
+        if self.raw.try_lock_upgradable() {
+            Some(UpgradableReadGuard {
+                rwlock: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+pub struct ReadGuard<'a, R: RawRwLock, T> {
+    rwlock: &'a RwLock<R, T>,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+impl<R: RawRwLock, T> Deref for ReadGuard<'_, R, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<R: RawRwLock, T> Drop for ReadGuard<'_, R, T> {
+    fn drop(&mut self) {
+        unsafe { self.rwlock.raw.unlock_shared() }
+    }
+}
+
+impl<'a, R: RawRwLock, T> ReadGuard<'a, R, T> {
+    /// Narrows the guard down to a part of the data while keeping it read locked.
+    pub fn map<U: ?Sized>(orig: Self, f: impl FnOnce(&T) -> &U) -> MappedReadGuard<'a, R, U> {
+        let value = NonNull::from(f(unsafe { &*orig.rwlock.value.get() }));
+        let orig = ManuallyDrop::new(orig);
+        MappedReadGuard {
+            raw: &orig.rwlock.raw,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Like `map()`, but gives the original guard back if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(
+        orig: Self,
+        f: impl FnOnce(&T) -> Option<&U>,
+    ) -> Result<MappedReadGuard<'a, R, U>, Self> {
+        match f(unsafe { &*orig.rwlock.value.get() }) {
+            Some(value) => {
+                let value = NonNull::from(value);
+                let orig = ManuallyDrop::new(orig);
+                Ok(MappedReadGuard {
+                    raw: &orig.rwlock.raw,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(orig),
+        }
+    }
+}
+
+/// A `ReadGuard` made by `ReadGuard::map()`, pointing to a part of the data.
+pub struct MappedReadGuard<'a, R: RawRwLock, T: ?Sized> {
+    raw: &'a R,
+    value: NonNull<T>,
+    _marker: PhantomData<&'a T>,
+}
+
+unsafe impl<R: RawRwLock + Sync, T: ?Sized + Sync> Sync for MappedReadGuard<'_, R, T> {}
+
+impl<R: RawRwLock, T: ?Sized> Deref for MappedReadGuard<'_, R, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<R: RawRwLock, T: ?Sized> Drop for MappedReadGuard<'_, R, T> {
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock_shared() }
+    }
+}
+
+impl<'a, R: RawRwLock, T: ?Sized> MappedReadGuard<'a, R, T> {
+    /// Narrows the guard down further, see `ReadGuard::map()`.
+    pub fn map<U: ?Sized>(orig: Self, f: impl FnOnce(&T) -> &U) -> MappedReadGuard<'a, R, U> {
+        let value = NonNull::from(f(unsafe { orig.value.as_ref() }));
+        let orig = ManuallyDrop::new(orig);
+        MappedReadGuard {
+            raw: orig.raw,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Like `map()`, but gives the original guard back if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(
+        orig: Self,
+        f: impl FnOnce(&T) -> Option<&U>,
+    ) -> Result<MappedReadGuard<'a, R, U>, Self> {
+        match f(unsafe { orig.value.as_ref() }) {
+            Some(value) => {
+                let value = NonNull::from(value);
+                let orig = ManuallyDrop::new(orig);
+                Ok(MappedReadGuard {
+                    raw: orig.raw,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(orig),
+        }
+    }
+}
+
+pub struct WriteGuard<'a, R: RawRwLock, T> {
+    rwlock: &'a RwLock<R, T>,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+impl<R: RawRwLock, T> Deref for WriteGuard<'_, R, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<R: RawRwLock, T> DerefMut for WriteGuard<'_, R, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<R: RawRwLock, T> Drop for WriteGuard<'_, R, T> {
+    fn drop(&mut self) {
+        unsafe { self.rwlock.raw.unlock_exclusive() }
+    }
+}
+
+impl<'a, R: RawRwLock, T> WriteGuard<'a, R, T> {
+    /// Narrows the guard down to a part of the data while keeping it write locked.
+    pub fn map<U: ?Sized>(
+        orig: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedWriteGuard<'a, R, U> {
+        let value = NonNull::from(f(unsafe { &mut *orig.rwlock.value.get() }));
+        let orig = ManuallyDrop::new(orig);
+        MappedWriteGuard {
+            raw: &orig.rwlock.raw,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Like `map()`, but gives the original guard back if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(
+        orig: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedWriteGuard<'a, R, U>, Self> {
+        match f(unsafe { &mut *orig.rwlock.value.get() }) {
+            Some(value) => {
+                let value = NonNull::from(value);
+                let orig = ManuallyDrop::new(orig);
+                Ok(MappedWriteGuard {
+                    raw: &orig.rwlock.raw,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(orig),
+        }
+    }
+}
+
+impl<'a, R: RawRwLockDowngrade, T> WriteGuard<'a, R, T> {
+    /// Turns this into a read lock, letting other readers in,
+    /// but no writer, so what was written is still there.
+    pub fn downgrade(s: Self) -> ReadGuard<'a, R, T> {
+        let s = ManuallyDrop::new(s);
+        unsafe { s.rwlock.raw.downgrade() };
+        ReadGuard {
+            rwlock: s.rwlock,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// A `WriteGuard` made by `WriteGuard::map()`, pointing to a part of the data.
+pub struct MappedWriteGuard<'a, R: RawRwLock, T: ?Sized> {
+    raw: &'a R,
+    value: NonNull<T>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<R: RawRwLock + Sync, T: ?Sized + Sync> Sync for MappedWriteGuard<'_, R, T> {}
+
+impl<R: RawRwLock, T: ?Sized> Deref for MappedWriteGuard<'_, R, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<R: RawRwLock, T: ?Sized> DerefMut for MappedWriteGuard<'_, R, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<R: RawRwLock, T: ?Sized> Drop for MappedWriteGuard<'_, R, T> {
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock_exclusive() }
+    }
+}
+
+impl<'a, R: RawRwLock, T: ?Sized> MappedWriteGuard<'a, R, T> {
+    /// Narrows the guard down further, see `WriteGuard::map()`.
+    pub fn map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedWriteGuard<'a, R, U> {
+        let value = NonNull::from(f(unsafe { orig.value.as_mut() }));
+        let orig = ManuallyDrop::new(orig);
+        MappedWriteGuard {
+            raw: orig.raw,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Like `map()`, but gives the original guard back if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedWriteGuard<'a, R, U>, Self> {
+        match f(unsafe { orig.value.as_mut() }) {
+            Some(value) => {
+                let value = NonNull::from(value);
+                let orig = ManuallyDrop::new(orig);
+                Ok(MappedWriteGuard {
+                    raw: orig.raw,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(orig),
+        }
+    }
+}
+
+/// A read lock that can be upgraded to a write lock, see `RwLock::upgradable_read()`.
+pub struct UpgradableReadGuard<'a, R: RawRwLockUpgrade, T> {
+    rwlock: &'a RwLock<R, T>,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+impl<R: RawRwLockUpgrade, T> Deref for UpgradableReadGuard<'_, R, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<R: RawRwLockUpgrade, T> Drop for UpgradableReadGuard<'_, R, T> {
+    fn drop(&mut self) {
+        unsafe { self.rwlock.raw.unlock_upgradable() }
+    }
+}
+
+impl<'a, R: RawRwLockUpgrade, T> UpgradableReadGuard<'a, R, T> {
+    /// Waits for the other readers to leave, and turns this into a write lock.
+    ///
+    /// Nobody else can write in between, so what was read is still up to date.
+    pub fn upgrade(s: Self) -> WriteGuard<'a, R, T> {
+        let s = ManuallyDrop::new(s);
+        unsafe { s.rwlock.raw.upgrade() };
+        WriteGuard {
+            rwlock: s.rwlock,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Like `upgrade()`, but gives the guard back if there are other readers.
+    pub fn try_upgrade(s: Self) -> Result<WriteGuard<'a, R, T>, Self> {
+        if unsafe { s.rwlock.raw.try_upgrade() } {
+            let s = ManuallyDrop::new(s);
+            Ok(WriteGuard {
+                rwlock: s.rwlock,
+                _marker: PhantomData,
+            })
+        } else {
+            Err(s)
+        }
+    }
+}
+
+unsafe impl<R: RawMutex, T> CondvarGuard for MutexGuard<'_, R, T> {
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        let raw = &self.mutex.raw;
+        with_unlocked(|| unsafe { raw.unlock() }, || raw.lock(), f)
+    }
+}
+
+unsafe impl<R: RawMutex, T: ?Sized> CondvarGuard for MappedMutexGuard<'_, R, T> {
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        let raw = self.raw;
+        with_unlocked(|| unsafe { raw.unlock() }, || raw.lock(), f)
+    }
+}
+
+unsafe impl<R: RawRwLock, T> CondvarGuard for ReadGuard<'_, R, T> {
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        let raw = &self.rwlock.raw;
+        with_unlocked(|| unsafe { raw.unlock_shared() }, || raw.lock_shared(), f)
+    }
+}
+
+unsafe impl<R: RawRwLock, T: ?Sized> CondvarGuard for MappedReadGuard<'_, R, T> {
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        let raw = self.raw;
+        with_unlocked(|| unsafe { raw.unlock_shared() }, || raw.lock_shared(), f)
+    }
+}
+
+unsafe impl<R: RawRwLock, T> CondvarGuard for WriteGuard<'_, R, T> {
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        let raw = &self.rwlock.raw;
+        with_unlocked(
+            || unsafe { raw.unlock_exclusive() },
+            || raw.lock_exclusive(),
+            f,
+        )
+    }
+}
+
+unsafe impl<R: RawRwLock, T: ?Sized> CondvarGuard for MappedWriteGuard<'_, R, T> {
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        let raw = self.raw;
+        with_unlocked(
+            || unsafe { raw.unlock_exclusive() },
+            || raw.lock_exclusive(),
+            f,
+        )
+    }
+}
+
+unsafe impl<R: RawRwLockUpgrade, T> CondvarGuard for UpgradableReadGuard<'_, R, T> {
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        let raw = &self.rwlock.raw;
+        with_unlocked(
+            || unsafe { raw.unlock_upgradable() },
+            || raw.lock_upgradable(),
+            f,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{
+            AtomicU32,
+            Ordering::{Acquire, Relaxed, Release},
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::{
+        GuardSend, Mutex, MutexGuard, RawRwLock, RawRwLockUpgrade, RwLock, UpgradableReadGuard,
+        WriteGuard,
+    };
+
+    /// A minimal reader-writer spin lock, to show what a custom algorithm gets for free.
+    ///
+    /// The state is the number of readers times 4, plus 2 for an upgradable reader,
+    /// or `WRITER`.
+    struct RawSpinRwLock {
+        state: AtomicU32,
+    }
+
+    const WRITER: u32 = 1;
+    const UPGRADABLE: u32 = 2;
+    const READER: u32 = 4;
+
+    impl RawSpinRwLock {
+        fn spin_until(&self, f: impl Fn(u32) -> Option<u32>) {
+            while !self.try_update(&f) {
+                std::hint::spin_loop();
+            }
+        }
+
+        fn try_update(&self, f: impl Fn(u32) -> Option<u32>) -> bool {
+            self.state.fetch_update(Acquire, Relaxed, f).is_ok()
+        }
+    }
+
+    unsafe impl RawRwLock for RawSpinRwLock {
+        const INIT: Self = Self {
+            state: AtomicU32::new(0),
+        };
+
+        type GuardMarker = GuardSend;
+
+        fn lock_shared(&self) {
+            self.spin_until(|s| (s & WRITER == 0).then_some(s + READER));
+        }
+
+        fn try_lock_shared(&self) -> bool {
+            self.try_update(|s| (s & WRITER == 0).then_some(s + READER))
+        }
+
+        unsafe fn unlock_shared(&self) {
+            self.state.fetch_sub(READER, Release);
+        }
+
+        fn lock_exclusive(&self) {
+            self.spin_until(|s| (s == 0).then_some(WRITER));
+        }
+
+        fn try_lock_exclusive(&self) -> bool {
+            self.try_update(|s| (s == 0).then_some(WRITER))
+        }
+
+        unsafe fn unlock_exclusive(&self) {
+            self.state.store(0, Release);
+        }
+    }
+
+    unsafe impl RawRwLockUpgrade for RawSpinRwLock {
+        fn lock_upgradable(&self) {
+            self.spin_until(|s| (s & (WRITER | UPGRADABLE) == 0).then_some(s + UPGRADABLE));
+        }
+
+        fn try_lock_upgradable(&self) -> bool {
+            self.try_update(|s| (s & (WRITER | UPGRADABLE) == 0).then_some(s + UPGRADABLE))
+        }
+
+        unsafe fn unlock_upgradable(&self) {
+            self.state.fetch_sub(UPGRADABLE, Release);
+        }
+
+        unsafe fn upgrade(&self) {
+            self.spin_until(|s| (s == UPGRADABLE).then_some(WRITER));
+        }
+
+        unsafe fn try_upgrade(&self) -> bool {
+            self.try_update(|s| (s == UPGRADABLE).then_some(WRITER))
+        }
+    }
+
+    #[test]
+    fn custom_rwlock() {
+        let lock = RwLock::<RawSpinRwLock, _>::new(vec![1]);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        let g = lock.upgradable_read();
+                        let len = g.len();
+                        // Nobody can push in between, so the length is still right.
+                        let mut g = UpgradableReadGuard::upgrade(g);
+                        g.push(len + 1);
+                    }
+                });
+            }
+        });
+        assert_eq!(lock.read().len(), 401);
+
+        let r = lock.read();
+        assert!(lock.try_write().is_none());
+        let u = lock.try_upgradable_read().unwrap();
+        assert!(lock.try_upgradable_read().is_none());
+        let u = UpgradableReadGuard::try_upgrade(u).err().unwrap();
+        drop(r);
+        let mut w = WriteGuard::map(UpgradableReadGuard::try_upgrade(u).ok().unwrap(), |v| {
+            &mut v[0]
+        });
+        *w = 0;
+        drop(w);
+        assert_eq!(lock.into_inner()[..3], [0, 2, 3]);
+    }
+
+    #[test]
+    fn futex_mutex() {
+        // The futex mutex without poisoning, with the timed and fair extras.
+        let m = Mutex::<crate::mutex::RawMutex, _>::new(0);
+        thread::scope(|s| {
+            let g = m.lock();
+            s.spawn(|| {
+                assert!(m.try_lock_for(Duration::from_millis(10)).is_none());
+                *m.lock() += 1;
+            });
+            thread::sleep(Duration::from_millis(100));
+            g.unlock_fair();
+        });
+        let v = MutexGuard::map(m.lock(), |v| v);
+        assert_eq!(*v, 1);
+    }
+}
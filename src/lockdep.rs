@@ -0,0 +1,277 @@
+//! Runtime lock order checking, like the Linux kernel's lockdep.
+//!
+//! Only active with the `lockdep` cargo feature in debug builds.
+//! Every lock gets a class the first time it's locked. Each thread keeps
+//! track of the classes it holds, and every blocking acquisition adds
+//! "held before" edges to one global graph. An edge that closes a cycle
+//! means two threads could deadlock by taking the same locks in a different
+//! order, even if it didn't happen this time. That is reported once,
+//! to `take_reports()`, which prints nothing: it's up to the caller,
+//! like a test, to check for reports and show them.
+//!
+//! Since the held locks are per thread, a lock must be unlocked on the thread
+//! that locked it, so the guards of all locks that lockdep tracks are `!Send`:
+//!
+//! ```compile_fail
+//! fn send<T: Send>(_: T) {}
+//!
+//! let lock = lock_learning::spin::SpinLock::new(0);
+//! send(lock.lock());
+//! ```
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+pub use enabled::{take_reports, Edge, Report};
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+pub(crate) use enabled::Class;
+
+#[cfg(not(all(feature = "lockdep", debug_assertions)))]
+pub(crate) use disabled::Class;
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+mod enabled {
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        fmt,
+        panic::Location,
+        sync::{
+            atomic::{AtomicUsize, Ordering::Relaxed},
+            Mutex,
+        },
+    };
+
+    /// "Lock `to` was acquired at `acquired_at` while holding lock `from`,
+    /// which was acquired at `held_at`."
+    #[derive(Debug, Clone, Copy)]
+    pub struct Edge {
+        pub from: usize,
+        pub to: usize,
+        pub held_at: &'static Location<'static>,
+        pub acquired_at: &'static Location<'static>,
+    }
+
+    impl fmt::Display for Edge {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "lock #{} acquired at {} while holding lock #{} acquired at {}",
+                self.to, self.acquired_at, self.from, self.held_at
+            )
+        }
+    }
+
+    /// A potential deadlock: `new` closes a cycle with the edges in `existing`.
+    #[derive(Debug, Clone)]
+    pub struct Report {
+        pub new: Edge,
+        /// The earlier acquisitions that lead from `new.to` back to `new.from`.
+        pub existing: Vec<Edge>,
+    }
+
+    impl fmt::Display for Report {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(f, "potential deadlock: inconsistent lock order")?;
+            writeln!(f, "  now:     {}", self.new)?;
+            for edge in &self.existing {
+                writeln!(f, "  earlier: {edge}")?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Uses `std::sync::Mutex`, since our own locks call into here.
+    struct Graph {
+        edges: HashMap<usize, Vec<Edge>>,
+        reports: Vec<Report>,
+    }
+
+    static GRAPH: Mutex<Option<Graph>> = Mutex::new(None);
+
+    thread_local! {
+        static HELD: RefCell<Vec<(usize, &'static Location<'static>)>> =
+            const { RefCell::new(Vec::new()) };
+    }
+
+    /// Returns the reports found so far, and forgets them.
+    pub fn take_reports() -> Vec<Report> {
+        let mut graph = GRAPH.lock().unwrap_or_else(|e| e.into_inner());
+        graph
+            .as_mut()
+            .map(|g| std::mem::take(&mut g.reports))
+            .unwrap_or_default()
+    }
+
+    /// The class of one lock. Assigned on first use, 0 means not yet.
+    pub(crate) struct Class {
+        id: AtomicUsize,
+    }
+
+    impl Class {
+        pub(crate) const fn new() -> Self {
+            Self {
+                id: AtomicUsize::new(0),
+            }
+        }
+
+        fn id(&self) -> usize {
+            static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+            match self.id.load(Relaxed) {
+                0 => {
+                    let new = NEXT_ID.fetch_add(1, Relaxed);
+                    match self.id.compare_exchange(0, new, Relaxed, Relaxed) {
+                        Ok(_) => new,
+                        Err(id) => id,
+                    }
+                }
+                id => id,
+            }
+        }
+
+        /// Called before a blocking acquisition, which is what could deadlock.
+        #[track_caller]
+        pub(crate) fn check(&self) {
+            let to = self.id();
+            let acquired_at = Location::caller();
+            let held = HELD.try_with(|h| h.borrow().clone()).unwrap_or_default();
+            if held.is_empty() {
+                return;
+            }
+            let mut graph = GRAPH.lock().unwrap_or_else(|e| e.into_inner());
+            let graph = graph.get_or_insert_with(|| Graph {
+                edges: HashMap::new(),
+                reports: Vec::new(),
+            });
+            for (from, held_at) in held {
+                if from == to {
+                    continue;
+                }
+                let known = graph
+                    .edges
+                    .get(&from)
+                    .is_some_and(|e| e.iter().any(|e| e.to == to));
+                if known {
+                    continue;
+                }
+                let new = Edge {
+                    from,
+                    to,
+                    held_at,
+                    acquired_at,
+                };
+                if let Some(existing) = graph.path(to, from) {
+                    graph.reports.push(Report { new, existing });
+                }
+                graph.edges.entry(from).or_default().push(new);
+            }
+        }
+
+        /// Called after the lock was acquired, blocking or not.
+        #[track_caller]
+        pub(crate) fn acquired(&self) {
+            let entry = (self.id(), Location::caller());
+            let _ = HELD.try_with(|h| h.borrow_mut().push(entry));
+        }
+
+        pub(crate) fn released(&self) {
+            let id = self.id();
+            let _ = HELD.try_with(|h| {
+                let mut h = h.borrow_mut();
+                // Locks don't have to be released in order.
+                if let Some(i) = h.iter().rposition(|&(c, _)| c == id) {
+                    h.remove(i);
+                }
+            });
+        }
+    }
+
+    impl Graph {
+        /// Depth first search for a chain of edges from `from` to `to`.
+        fn path(&self, from: usize, to: usize) -> Option<Vec<Edge>> {
+            let mut visited = vec![from];
+            let mut stack: Vec<Vec<Edge>> = vec![Vec::new()];
+            while let Some(path) = stack.pop() {
+                let last = path.last().map_or(from, |e| e.to);
+                for edge in self.edges.get(&last).into_iter().flatten() {
+                    if edge.to == to {
+                        let mut path = path.clone();
+                        path.push(*edge);
+                        return Some(path);
+                    }
+                    if !visited.contains(&edge.to) {
+                        visited.push(edge.to);
+                        let mut path = path.clone();
+                        path.push(*edge);
+                        stack.push(path);
+                    }
+                }
+            }
+            None
+        }
+    }
+}
+
+#[cfg(not(all(feature = "lockdep", debug_assertions)))]
+mod disabled {
+    pub(crate) struct Class;
+
+    impl Class {
+        pub(crate) const fn new() -> Self {
+            Self
+        }
+        #[inline(always)]
+        pub(crate) fn check(&self) {}
+        #[inline(always)]
+        pub(crate) fn acquired(&self) {}
+        #[inline(always)]
+        pub(crate) fn released(&self) {}
+    }
+}
+
+#[cfg(all(test, feature = "lockdep", debug_assertions))]
+mod test {
+    use std::thread;
+
+    use super::take_reports;
+    use crate::{mutex::Mutex, read_write_lock::RwLock, spin::SpinLock};
+
+    fn reports_from_here() -> Vec<super::Report> {
+        take_reports()
+            .into_iter()
+            .filter(|r| r.new.acquired_at.file() == file!())
+            .collect()
+    }
+
+    #[test]
+    fn inversion() {
+        let a = Mutex::new(0);
+        let b = RwLock::new(0);
+        let c = SpinLock::new(0);
+
+        // Consistent order: a, b, c.
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _a = a.lock().unwrap();
+                let _b = b.write().unwrap();
+                let _c = c.lock();
+            });
+        });
+        assert!(reports_from_here().is_empty());
+
+        // c then a never deadlocked here, but could have.
+        let _c = c.lock();
+        let _a = a.lock().unwrap();
+        let reports = reports_from_here();
+        assert_eq!(reports.len(), 1);
+        // c -> a closes the cycle through a -> c (or a -> b -> c).
+        assert!(!reports[0].existing.is_empty());
+        let text = reports[0].to_string();
+        assert!(text.contains("potential deadlock"), "{text}");
+        assert!(text.contains(file!()), "{text}");
+
+        // The same inversion is only reported once.
+        drop(_a);
+        let _a = a.lock().unwrap();
+        assert!(reports_from_here().is_empty());
+    }
+}
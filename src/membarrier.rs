@@ -0,0 +1,398 @@
+//! A reader-writer lock for read paths that run millions of times per second
+//! and writers that are rare, using Linux's `membarrier()`.
+//!
+//! Like in `bravo`, a reader writes to a slot of its own instead of to the lock,
+//! but here every thread has its own slot, and a reader doesn't even need an
+//! atomic read-modify-write or a fence: it stores the lock's address in its
+//! slot, and then checks that no writer is there, with only a compiler fence in
+//! between. On its own, the CPU could still do that check before the store, so
+//! a reader and a writer could miss each other. The writer makes up for that:
+//! after announcing itself, it calls `membarrier(MEMBARRIER_CMD_PRIVATE_EXPEDITED)`,
+//! which runs a full memory barrier on every CPU that is running one of our
+//! threads, and only then waits until no slot refers to the lock anymore.
+//!
+//! The process registers for `membarrier()` the first time it's needed. Where
+//! it's not available, readers use a real `fence(SeqCst)` instead.
+//!
+//! A thread claims its slot the first time it reads, and gives it back when it
+//! exits. It can only hold one lock through its slot at a time, so nested read
+//! locks, and threads beyond the first `SLOTS`, count themselves in the lock's
+//! `slow_readers` instead. Since unlocking looks at the current thread's slot,
+//! the guards can't be sent to another thread.
+//!
+//! `RwLock` is `read_write_lock::RwLock` on this algorithm, so it has the same
+//! guards and poisoning:
+//!
+//! ```
+//! use lock_learning::membarrier::{RawRwLock, RwLock};
+//!
+//! let lock = RwLock::from_raw(RawRwLock::new(), 1);
+//! assert_eq!(*lock.read().unwrap(), 1);
+//! *lock.write().unwrap() += 1;
+//! ```
+
+use std::{
+    cell::Cell,
+    hint,
+    sync::{
+        atomic::{
+            compiler_fence, fence, AtomicU32, AtomicUsize,
+            Ordering::{Acquire, Relaxed, Release, SeqCst},
+        },
+        OnceLock,
+    },
+    thread,
+};
+
+use atomic_wait::{wait, wake_all};
+
+use crate::{
+    lock_api::{self, RawMutex as _},
+    mutex, read_write_lock,
+    spin::SpinLock,
+};
+
+/// From `linux/membarrier.h`, which the `libc` crate doesn't have.
+const MEMBARRIER_CMD_PRIVATE_EXPEDITED: libc::c_int = 1 << 3;
+const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED: libc::c_int = 1 << 4;
+
+/// The number of slots, shared by all locks.
+const SLOTS: usize = 1024;
+
+/// `state` while a writer holds the lock or waits for the readers to leave.
+const WRITER: u32 = 1;
+/// Added to `WRITER` by readers that go to sleep until the writer is done.
+const READERS_WAITING: u32 = 2;
+
+/// Holds the address of the lock a thread has read locked through it, or 0.
+/// Only written by its thread. On its own cache line, so threads don't interfere.
+#[repr(align(64))]
+struct Slot(AtomicUsize);
+
+static READER_SLOTS: [Slot; SLOTS] = [const { Slot(AtomicUsize::new(0)) }; SLOTS];
+/// One past the highest slot ever claimed, so writers don't scan the rest.
+static SLOTS_IN_USE: AtomicUsize = AtomicUsize::new(0);
+/// Slots given back by threads that exited.
+static FREE_SLOTS: SpinLock<Vec<usize>> = SpinLock::new(Vec::new());
+
+/// The lock part of `RwLock`, without the data.
+pub struct RawRwLock {
+    /// 0, `WRITER`, or `WRITER | READERS_WAITING`.
+    state: AtomicU32,
+    /// The number of read locks held without a slot.
+    slow_readers: AtomicU32,
+    /// Writers take turns, so `state` only needs to tell readers about one.
+    writer: mutex::RawMutex,
+}
+
+pub type RwLock<T> = read_write_lock::RwLock<T, RawRwLock>;
+pub type ReadGuard<'a, T> = read_write_lock::ReadGuard<'a, T, RawRwLock>;
+pub type MappedReadGuard<'a, T> = read_write_lock::MappedReadGuard<'a, T, RawRwLock>;
+pub type WriteGuard<'a, T> = read_write_lock::WriteGuard<'a, T, RawRwLock>;
+pub type MappedWriteGuard<'a, T> = read_write_lock::MappedWriteGuard<'a, T, RawRwLock>;
+
+impl Default for RawRwLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RawRwLock {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            slow_readers: AtomicU32::new(0),
+            writer: mutex::RawMutex::new(),
+        }
+    }
+
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Sleeps until the current writer is done.
+    fn wait_for_writer(&self) {
+        let mut s = self.state.load(Relaxed);
+        while s != 0 {
+            if s == WRITER {
+                if let Err(e) =
+                    self.state
+                        .compare_exchange(WRITER, WRITER | READERS_WAITING, Relaxed, Relaxed)
+                {
+                    s = e;
+                    continue;
+                }
+            }
+            wait(&self.state, WRITER | READERS_WAITING);
+            s = self.state.load(Relaxed);
+        }
+    }
+
+    /// Must only be called by the writer, after setting `state`.
+    fn wait_for_readers(&self) {
+        heavy_fence();
+        let mut attempt = 0u32;
+        // Readers only leave their slot, they don't wake us, and they're
+        // supposed to be short, so spin (and yield) rather than sleep.
+        while self.has_readers() {
+            if attempt < 100 {
+                hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+            attempt = attempt.saturating_add(1);
+        }
+    }
+
+    fn has_readers(&self) -> bool {
+        // Acquire matches the Release in `unlock_shared()`, so we
+        // see everything the readers did before they left.
+        self.slow_readers.load(Acquire) > 0
+            || READER_SLOTS[..SLOTS_IN_USE.load(SeqCst)]
+                .iter()
+                .any(|slot| slot.0.load(Acquire) == self.id())
+    }
+}
+
+unsafe impl lock_api::RawRwLock for RawRwLock {
+    const INIT: Self = Self::new();
+
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock_shared(&self) {
+        while !self.try_lock_shared() {
+            self.wait_for_writer();
+        }
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        match thread_slot() {
+            Some(slot) if slot.0.load(Relaxed) == 0 => {
+                slot.0.store(self.id(), Relaxed);
+                // Together with `heavy_fence()` in `wait_for_readers()`:
+                // either the writer sees our slot, or we see the writer.
+                light_fence();
+                // Acquire matches the Release in `unlock_exclusive()`,
+                // so we see what the last writer wrote.
+                if self.state.load(Acquire) == 0 {
+                    return true;
+                }
+                slot.0.store(0, Relaxed);
+                false
+            }
+            _ => {
+                // SeqCst, like the fence in `heavy_fence()`.
+                self.slow_readers.fetch_add(1, SeqCst);
+                if self.state.load(SeqCst) == 0 {
+                    return true;
+                }
+                self.slow_readers.fetch_sub(1, Relaxed);
+                false
+            }
+        }
+    }
+
+    unsafe fn unlock_shared(&self) {
+        // If this thread holds the lock both ways, it doesn't
+        // matter which one we give up first.
+        match thread_slot() {
+            Some(slot) if slot.0.load(Relaxed) == self.id() => slot.0.store(0, Release),
+            _ => {
+                self.slow_readers.fetch_sub(1, Release);
+            }
+        }
+    }
+
+    fn lock_exclusive(&self) {
+        self.writer.lock();
+        self.state.store(WRITER, Relaxed);
+        self.wait_for_readers();
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        if !self.writer.try_lock() {
+            return false;
+        }
+        self.state.store(WRITER, Relaxed);
+        heavy_fence();
+        if self.has_readers() {
+            unsafe { lock_api::RawRwLock::unlock_exclusive(self) };
+            return false;
+        }
+        true
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        if self.state.swap(0, Release) & READERS_WAITING != 0 {
+            wake_all(&self.state);
+        }
+        self.writer.unlock();
+    }
+}
+
+/// The current thread's slot, or `None` if all of them are taken
+/// (or the thread is exiting and already gave it back).
+///
+/// This keeps working while the thread's other thread locals are destroyed,
+/// so a read guard dropped by one of their destructors still finds the slot
+/// it was locked through.
+fn thread_slot() -> Option<&'static Slot> {
+    /// `INDEX` before the thread claimed a slot.
+    const UNCLAIMED: usize = usize::MAX;
+    /// `INDEX` if the thread has no slot.
+    const NO_SLOT: usize = usize::MAX - 1;
+
+    /// Gives the slot back when the thread exits.
+    struct GiveBack;
+
+    impl Drop for GiveBack {
+        fn drop(&mut self) {
+            INDEX.with(|index| {
+                let i = index.get();
+                // A read guard that's still held (or leaked) keeps the slot,
+                // so no other thread takes it over before it's unlocked.
+                if i < SLOTS && READER_SLOTS[i].0.load(Relaxed) == 0 {
+                    index.set(NO_SLOT);
+                    FREE_SLOTS.lock().push(i);
+                }
+            });
+        }
+    }
+
+    thread_local! {
+        /// Has no destructor, so it's never gone before the thread is.
+        static INDEX: Cell<usize> = const { Cell::new(UNCLAIMED) };
+        static GIVE_BACK: GiveBack = const { GiveBack };
+    }
+
+    INDEX.with(|index| {
+        // If `GIVE_BACK` is already gone, the slot would never be given back.
+        if index.get() == UNCLAIMED && GIVE_BACK.try_with(|_| ()).is_ok() {
+            let claimed = FREE_SLOTS.lock().pop().or_else(|| {
+                // SeqCst, so a writer that doesn't see the new slot
+                // is one that this thread's reads will see.
+                SLOTS_IN_USE
+                    .fetch_update(SeqCst, SeqCst, |n| (n < SLOTS).then_some(n + 1))
+                    .ok()
+            });
+            index.set(claimed.unwrap_or(NO_SLOT));
+        }
+        READER_SLOTS.get(index.get())
+    })
+}
+
+/// Whether the process is registered for `MEMBARRIER_CMD_PRIVATE_EXPEDITED`,
+/// which it tries the first time this is called.
+fn registered() -> bool {
+    static REGISTERED: OnceLock<bool> = OnceLock::new();
+    *REGISTERED.get_or_init(|| {
+        let r = unsafe {
+            libc::syscall(
+                libc::SYS_membarrier,
+                MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED,
+                0,
+                0,
+            )
+        };
+        r == 0
+    })
+}
+
+/// The readers' side: only stops the compiler from reordering,
+/// unless `membarrier()` isn't available.
+fn light_fence() {
+    if registered() {
+        compiler_fence(SeqCst);
+    } else {
+        fence(SeqCst);
+    }
+}
+
+/// The writer's side: a `fence(SeqCst)` on every thread of the process,
+/// so it pairs with `light_fence()` as if that were a real fence.
+fn heavy_fence() {
+    fence(SeqCst);
+    if registered() {
+        let r =
+            unsafe { libc::syscall(libc::SYS_membarrier, MEMBARRIER_CMD_PRIVATE_EXPEDITED, 0, 0) };
+        assert!(
+            r == 0,
+            "MEMBARRIER_CMD_PRIVATE_EXPEDITED failed: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::RefCell,
+        sync::atomic::{AtomicBool, Ordering::Relaxed},
+        thread,
+    };
+
+    use super::{thread_slot, RawRwLock, ReadGuard, RwLock};
+
+    #[test]
+    fn slots() {
+        let lock = RwLock::from_raw(RawRwLock::new(), (1, 2));
+        let a = lock.read().unwrap();
+        assert!(thread_slot().is_some());
+        // Our slot is taken, so this one is counted in `slow_readers`.
+        let b = ReadGuard::map(lock.read().unwrap(), |v| &v.1);
+        assert_eq!((a.0, *b), (1, 2));
+        assert!(lock.try_write().is_err());
+        drop(a);
+        assert!(lock.try_write().is_err());
+        drop(b);
+        lock.write().unwrap().0 = 3;
+        assert_eq!(*lock.read().unwrap(), (3, 2));
+    }
+
+    #[test]
+    fn readers_and_writers() {
+        let lock = RwLock::from_raw(RawRwLock::new(), (0, 0));
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(Relaxed) {
+                        let g = lock.read().unwrap();
+                        assert_eq!(g.0, g.1);
+                        drop(g);
+                        // Don't hog the CPU if there are fewer cores than threads.
+                        thread::yield_now();
+                    }
+                });
+            }
+            for _ in 0..1000 {
+                let mut g = lock.write().unwrap();
+                g.0 += 1;
+                thread::yield_now();
+                g.1 += 1;
+            }
+            done.store(true, Relaxed);
+        });
+        assert_eq!(lock.into_inner().unwrap(), (1000, 1000));
+    }
+
+    #[test]
+    fn guard_dropped_at_thread_exit() {
+        static LOCK: RwLock<i32> = RwLock::from_raw(RawRwLock::new(), 0);
+        thread_local! {
+            static HELD: RefCell<Option<ReadGuard<'static, i32>>> = const { RefCell::new(None) };
+        }
+        thread::spawn(|| {
+            // Thread locals are destroyed in reverse order, so by touching
+            // this one before the slot is claimed, the guard is dropped
+            // after the thread's other thread locals are gone.
+            HELD.with(|_| ());
+            let g = LOCK.read().unwrap();
+            assert!(thread_slot().is_some());
+            HELD.with(|h| *h.borrow_mut() = Some(g));
+        })
+        .join()
+        .unwrap();
+        assert!(LOCK.try_write().is_ok());
+    }
+}
@@ -1,60 +1,201 @@
 use std::{
-    cell::UnsafeCell,
+    any::Any,
+    mem::ManuallyDrop,
     ops::{Deref, DerefMut},
+    ptr,
     sync::atomic::{
-        AtomicU32,
+        AtomicBool, AtomicU32,
         Ordering::{Acquire, Relaxed, Release},
     },
+    time::{Duration, Instant},
 };
 
-use atomic_wait::{wait, wake_one};
+use atomic_wait::wake_one;
 
-pub struct Mutex<T> {
+use crate::{
+    backoff::{Spin, SpinPolicy},
+    condition_variable::CondvarGuard,
+    futex,
+    lock_api::{self, RawMutex as _},
+    lockdep,
+    poison::{self, LockResult, TryLockError, TryLockResult},
+    stats::{LockStats, Timer},
+};
+
+/// The lock part of `Mutex`, without the data.
+pub struct RawMutex {
     /// State to indicate Lock:
     /// - 0: unlocked
     /// - 1: locked, no other threads waiting
     /// - 2: locked, other threads waiting
+    /// - 3: locked, handed over to one of the waiting threads by a fair unlock
     state: AtomicU32,
-    value: UnsafeCell<T>,
+    /// Set by a waiter that has waited longer than `max_wait`,
+    /// to make the next unlock a fair one.
+    starving: AtomicBool,
+    max_wait: Option<Duration>,
+    /// How long to spin while the lock is held before going to sleep.
+    spin: &'static dyn SpinPolicy,
+    stats: LockStats,
+    lockdep: lockdep::Class,
 }
 
-unsafe impl<T> Sync for Mutex<T> where T: Send {}
-
-pub struct MutexGuard<'a, T> {
-    pub(crate) mutex: &'a Mutex<T>,
+impl Default for RawMutex {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<T> Deref for MutexGuard<'_, T> {
-    type Target = T;
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.mutex.value.get() }
+impl RawMutex {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            starving: AtomicBool::new(false),
+            max_wait: None,
+            spin: &Spin { limit: 100 },
+            stats: LockStats::new(),
+            lockdep: lockdep::Class::new(),
+        }
     }
-}
 
-impl<T> DerefMut for MutexGuard<'_, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.mutex.value.get() }
+    /// The futex that `Condvar::notify_all()` moves its waiters to.
+    pub(crate) fn futex(&self) -> &AtomicU32 {
+        &self.state
     }
-}
 
-impl<T> Mutex<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            state: AtomicU32::new(0),
-            value: UnsafeCell::new(value),
+    /// Locks again in `Condvar::wait()`, after the thread might have been
+    /// requeued onto `state` by `Condvar::notify_all()`.
+    ///
+    /// Other requeued threads may be asleep on `state`, but nobody set it to 2
+    /// for them, so this always takes the lock as 2, like `lock_contended()` does.
+    #[track_caller]
+    pub(crate) fn lock_requeued(&self) {
+        self.lockdep.check();
+        self.lock_contended(None, true);
+        self.lockdep.acquired();
+    }
+
+    /// Returns `false` if `deadline` passed before we got the lock.
+    ///
+    /// Giving up leaves state at 2 even if we were the last waiter,
+    /// which only costs the next unlock a spurious `wake_one()`.
+    fn lock_contended(&self, deadline: Option<Instant>, requeued: bool) -> bool {
+        let timer = Timer::start();
+        self.stats.contended();
+        let locked = self.lock_contended_inner(deadline, requeued);
+        if locked {
+            self.stats.acquired();
         }
+        self.stats.waited(timer);
+        locked
     }
 
-    pub fn lock(&self) -> MutexGuard<T> {
+    fn lock_contended_inner(&self, deadline: Option<Instant>, requeued: bool) -> bool {
+        let state = &self.state;
+
+        // Taking the lock as 1 would make the unlock skip waking the next requeued thread.
+        if !requeued {
+            let mut attempt = 0u32;
+            while state.load(Relaxed) == 1 && self.spin.backoff(attempt) {
+                attempt = attempt.saturating_add(1);
+            }
+            self.stats.spins(attempt);
+
+            if state.compare_exchange(0, 1, Acquire, Relaxed).is_ok() {
+                return true;
+            }
+        }
+
+        let starving_at = self.max_wait.and_then(|d| Instant::now().checked_add(d));
+        // Whether we set `starving`, which we have to clear again however we leave.
+        let mut starved = false;
+        // Only a thread that has been waiting may take a lock handed over in state 3.
+        // A requeued thread might have been woken for it by `unlock_fair()`.
+        let mut woken = requeued;
+        let locked = loop {
+            let s = state.load(Relaxed);
+            match s {
+                0 => {
+                    if state.compare_exchange(0, 2, Acquire, Relaxed).is_ok() {
+                        break true;
+                    }
+                    continue;
+                }
+                1 if state.compare_exchange(1, 2, Relaxed, Relaxed).is_err() => continue,
+                3 if woken => {
+                    if state.compare_exchange(3, 2, Acquire, Relaxed).is_ok() {
+                        self.starving.store(false, Relaxed);
+                        break true;
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            let wait_deadline = match starving_at {
+                Some(t) if Instant::now() >= t => {
+                    self.starving.store(true, Relaxed);
+                    starved = true;
+                    deadline
+                }
+                Some(t) => Some(deadline.map_or(t, |d| d.min(t))),
+                None => deadline,
+            };
+            let expected = if s == 3 { 3 } else { 2 };
+            self.stats.futex_wait();
+            if !futex::wait_until(state, expected, wait_deadline)
+                && deadline.is_some_and(|d| Instant::now() >= d)
+            {
+                // Don't leave a lock that was just handed to us stuck in state 3.
+                break state.compare_exchange(3, 2, Acquire, Relaxed).is_ok();
+            }
+            woken = true;
+        };
+        // Whether we got the lock or gave up, we're not starving anymore.
+        // Another starving waiter sets it again before it goes back to sleep.
+        if starved {
+            self.starving.store(false, Relaxed);
+        }
+        locked
+    }
+
+    /// `unlock_fair()` without the bookkeeping.
+    fn hand_over(&self) {
+        if self.state.compare_exchange(1, 0, Release, Relaxed).is_ok() {
+            return;
+        }
+        // State is 2. Keep it locked, so only a thread that has been waiting can take it.
+        self.state.store(3, Release);
+        self.stats.futex_wake();
+        if futex::wake(&self.state, 1) == 0 {
+            // Nobody was asleep, all waiters might have timed out.
+            // Unlock for real, unless a waiter has taken it in the meantime.
+            if self.state.compare_exchange(3, 0, Release, Relaxed).is_ok() {
+                // A waiter that saw 3 may have gone to sleep on it right after
+                // our wake, and no unlock of the now free lock would wake it.
+                self.stats.futex_wake();
+                wake_one(&self.state);
+            }
+        }
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawMutex {
+    const INIT: Self = Self::new();
+
+    // Lockdep tracks the held locks per thread.
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock(&self) {
         // compare_exchange from 0 to 1:
         // - if success, then state is actually 0(unlocked), get the lock
-        // - else, state is 1 or 2 (locked).
+        // - else, state is 1, 2 or 3 (locked).
         //
-        // In that situation, swap 2 into state:
+        // In that situation, move state to 2:
         //  - if state is 1, then it move to 2 now
         //  - if state is 2, nothing happen
         //
-        // After swap, wait for state become not 2, and check again,
+        // After that, wait for state become not 2, and check again,
         // - if state is 0, then we got the locked and move state to 2?
         // - if state is not 0, means other thread got the lock before
         //
@@ -63,36 +204,516 @@ impl<T> Mutex<T> {
         // state needs to become 2 to avoid lost of wait().
         // But if we don't have thread get into state 2, then it's safe
         // to just avoid wait() and wake_one()
-        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
-            lock_contended(&self.state);
+        self.lockdep.check();
+        if !self.try_lock() {
+            self.lock_contended(None, false);
+            self.lockdep.acquired();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        let locked = self.try_lock_quiet();
+        if locked {
+            self.stats.uncontended();
+            self.stats.acquired();
+            self.lockdep.acquired();
+        }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        self.lockdep.released();
+        self.stats.released();
+        self.unlock_quiet();
+    }
+
+    fn try_lock_quiet(&self) -> bool {
+        self.state.compare_exchange(0, 1, Acquire, Relaxed).is_ok()
+    }
+
+    unsafe fn unlock_quiet(&self) {
+        if self.starving.load(Relaxed) {
+            return self.hand_over();
+        }
+        // Wake up one of the waiting threads, if any.
+        if self.state.swap(0, Release) == 2 {
+            self.stats.futex_wake();
+            wake_one(&self.state);
         }
-        MutexGuard { mutex: self }
     }
 }
 
-fn lock_contended(state: &AtomicU32) {
-    const SPIN_LIMIT: usize = 100;
-    let mut spin_count = 0;
+unsafe impl lock_api::RawMutexFair for RawMutex {
+    unsafe fn unlock_fair(&self) {
+        self.lockdep.released();
+        self.stats.released();
+        self.hand_over();
+    }
+}
 
-    while state.load(Relaxed) == 1 && spin_count < SPIN_LIMIT {
-        spin_count += 1;
-        std::hint::spin_loop();
+unsafe impl lock_api::RawMutexTimed for RawMutex {
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        self.lockdep.check();
+        if self.try_lock() {
+            return true;
+        }
+        let locked = self.lock_contended(Some(deadline), false);
+        if locked {
+            self.lockdep.acquired();
+        }
+        locked
     }
+}
+
+/// A `lock_api::Mutex` on the futex lock, with poisoning like `std::sync::Mutex`.
+///
+/// `R` is `RawMutex` unless another lock algorithm is plugged in with `from_raw()`,
+/// like `pi_mutex::RawPiMutex`.
+pub struct Mutex<T, R: lock_api::RawMutex = RawMutex> {
+    pub(crate) inner: lock_api::Mutex<R, T>,
+    poison: poison::Flag,
+}
+
+pub struct MutexGuard<'a, T, R: lock_api::RawMutex = RawMutex> {
+    mutex: &'a Mutex<T, R>,
+    /// Dropped after `Drop::drop()`, so the poison flag is set before unlocking.
+    inner: lock_api::MutexGuard<'a, R, T>,
+    poison: poison::Guard,
+}
 
-    if state.compare_exchange(0, 1, Acquire, Relaxed).is_ok() {
-        return;
+impl<T, R: lock_api::RawMutex> Deref for MutexGuard<'_, T, R> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
     }
+}
 
-    while state.swap(2, Acquire) != 0 {
-        wait(state, 2);
+impl<T, R: lock_api::RawMutex> DerefMut for MutexGuard<'_, T, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
     }
 }
 
-impl<T> Drop for MutexGuard<'_, T> {
+impl<T, R: lock_api::RawMutex> Drop for MutexGuard<'_, T, R> {
     fn drop(&mut self) {
-        // Wake up one of the waiting threads, if any.
-        if self.mutex.state.swap(0, Release) == 2 {
-            wake_one(&self.mutex.state);
+        self.mutex.poison.done(&self.poison);
+    }
+}
+
+impl<'a, T, R: lock_api::RawMutexFair> MutexGuard<'a, T, R> {
+    /// Unlocks the mutex and hands it directly to a waiting thread, if any,
+    /// so this thread can't take it right back before the woken thread runs.
+    pub fn unlock_fair(self) {
+        let (inner, poison_flag, poison) = self.into_parts();
+        poison_flag.done(&poison);
+        inner.unlock_fair();
+    }
+}
+
+impl<'a, T, R: lock_api::RawMutex> MutexGuard<'a, T, R> {
+    /// Narrows the guard down to a part of the data, like one field,
+    /// while keeping the mutex locked.
+    ///
+    /// This is an associated function so it can't shadow a method of `T`,
+    /// call it as `MutexGuard::map(guard, |v| &mut v.field)`.
+    pub fn map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedMutexGuard<'a, U, R> {
+        // `orig` stays alive while `f` runs, so a panic in `f` unlocks (and poisons).
+        let value: *mut U = f(&mut orig);
+        let (inner, poison_flag, poison) = orig.into_parts();
+        MappedMutexGuard {
+            inner: lock_api::MutexGuard::map(inner, |_| unsafe { &mut *value }),
+            poison_flag,
+            poison,
         }
     }
+
+    /// Like `map()`, but gives the original guard back if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedMutexGuard<'a, U, R>, Self> {
+        let value: *mut U = match f(&mut orig) {
+            Some(value) => value,
+            None => return Err(orig),
+        };
+        let (inner, poison_flag, poison) = orig.into_parts();
+        Ok(MappedMutexGuard {
+            inner: lock_api::MutexGuard::map(inner, |_| unsafe { &mut *value }),
+            poison_flag,
+            poison,
+        })
+    }
+
+    /// Takes the guard apart without unlocking or touching the poison flag.
+    fn into_parts(
+        self,
+    ) -> (
+        lock_api::MutexGuard<'a, R, T>,
+        &'a poison::Flag,
+        poison::Guard,
+    ) {
+        let guard = ManuallyDrop::new(self);
+        // Safety: `guard` is never used or dropped again.
+        let inner = unsafe { ptr::read(&guard.inner) };
+        (inner, &guard.mutex.poison, guard.poison)
+    }
+}
+
+/// A `MutexGuard` made by `MutexGuard::map()`, pointing to a part of the data.
+pub struct MappedMutexGuard<'a, T: ?Sized, R: lock_api::RawMutex = RawMutex> {
+    inner: lock_api::MappedMutexGuard<'a, R, T>,
+    poison_flag: &'a poison::Flag,
+    poison: poison::Guard,
+}
+
+impl<T: ?Sized, R: lock_api::RawMutex> Deref for MappedMutexGuard<'_, T, R> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized, R: lock_api::RawMutex> DerefMut for MappedMutexGuard<'_, T, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<T: ?Sized, R: lock_api::RawMutex> Drop for MappedMutexGuard<'_, T, R> {
+    fn drop(&mut self) {
+        self.poison_flag.done(&self.poison);
+    }
+}
+
+impl<'a, T: ?Sized, R: lock_api::RawMutexFair> MappedMutexGuard<'a, T, R> {
+    /// See `MutexGuard::unlock_fair()`.
+    pub fn unlock_fair(self) {
+        let (inner, poison_flag, poison) = self.into_parts();
+        poison_flag.done(&poison);
+        inner.unlock_fair();
+    }
+}
+
+impl<'a, T: ?Sized, R: lock_api::RawMutex> MappedMutexGuard<'a, T, R> {
+    /// Narrows the guard down further, see `MutexGuard::map()`.
+    pub fn map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedMutexGuard<'a, U, R> {
+        let value: *mut U = f(&mut orig);
+        let (inner, poison_flag, poison) = orig.into_parts();
+        MappedMutexGuard {
+            inner: lock_api::MappedMutexGuard::map(inner, |_| unsafe { &mut *value }),
+            poison_flag,
+            poison,
+        }
+    }
+
+    /// Like `map()`, but gives the original guard back if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedMutexGuard<'a, U, R>, Self> {
+        let value: *mut U = match f(&mut orig) {
+            Some(value) => value,
+            None => return Err(orig),
+        };
+        let (inner, poison_flag, poison) = orig.into_parts();
+        Ok(MappedMutexGuard {
+            inner: lock_api::MappedMutexGuard::map(inner, |_| unsafe { &mut *value }),
+            poison_flag,
+            poison,
+        })
+    }
+
+    /// Takes the guard apart without unlocking or touching the poison flag.
+    fn into_parts(
+        self,
+    ) -> (
+        lock_api::MappedMutexGuard<'a, R, T>,
+        &'a poison::Flag,
+        poison::Guard,
+    ) {
+        let guard = ManuallyDrop::new(self);
+        // Safety: `guard` is never used or dropped again.
+        let inner = unsafe { ptr::read(&guard.inner) };
+        (inner, guard.poison_flag, guard.poison)
+    }
+}
+
+/// The futex behind `raw`, if it's our own futex mutex: only then can
+/// `Condvar::notify_all()` requeue its waiters onto it.
+fn futex_mutex<R: lock_api::RawMutex + 'static>(raw: &R) -> Option<&RawMutex> {
+    (raw as &dyn Any).downcast_ref()
+}
+
+unsafe impl<T, R: lock_api::RawMutex + 'static> CondvarGuard for MutexGuard<'_, T, R> {
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        self.inner.unlocked(f)
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.mutex.poison.get()
+    }
+
+    fn raw_mutex(&self) -> Option<&RawMutex> {
+        futex_mutex(&self.mutex.inner.raw)
+    }
+}
+
+unsafe impl<T: ?Sized, R: lock_api::RawMutex + 'static> CondvarGuard
+    for MappedMutexGuard<'_, T, R>
+{
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        self.inner.unlocked(f)
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.poison_flag.get()
+    }
+
+    fn raw_mutex(&self) -> Option<&RawMutex> {
+        futex_mutex(self.inner.raw)
+    }
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::from_raw(RawMutex::new(), value)
+    }
+
+    /// Makes the mutex eventually fair: once a thread has been waiting
+    /// for longer than `max_wait`, the next unlock is a fair one,
+    /// see `MutexGuard::unlock_fair()`.
+    pub const fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.inner.raw.max_wait = Some(max_wait);
+        self
+    }
+
+    /// Replaces the default policy of spinning 100 times before going to sleep.
+    pub const fn with_spin_policy(mut self, spin: &'static dyn SpinPolicy) -> Self {
+        self.inner.raw.spin = spin;
+        self
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::StatsSnapshot {
+        self.inner.raw.stats.snapshot()
+    }
+}
+
+impl<T, R: lock_api::RawMutex> Mutex<T, R> {
+    /// For another lock algorithm than `RawMutex`, or one that was configured.
+    pub const fn from_raw(raw: R, value: T) -> Self {
+        Self {
+            inner: lock_api::Mutex::from_raw(raw, value),
+            poison: poison::Flag::new(),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T, R>> {
+        self.guard(self.inner.lock())
+    }
+
+    /// Returns `WouldBlock` instead of blocking if the lock is held.
+    #[track_caller]
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T, R>> {
+        match self.inner.try_lock() {
+            Some(inner) => Ok(self.guard(inner)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    fn guard<'a>(
+        &'a self,
+        inner: lock_api::MutexGuard<'a, R, T>,
+    ) -> LockResult<MutexGuard<'a, T, R>> {
+        poison::map_result(self.poison.guard(), |poison| MutexGuard {
+            mutex: self,
+            inner,
+            poison,
+        })
+    }
+
+    /// Whether a thread panicked while holding the lock.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Call this after the data has been brought back into a consistent state.
+    pub fn clear_poison(&self) {
+        self.poison.clear()
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let value = self.inner.get_mut();
+        if self.poison.get() {
+            Err(poison::PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        let value = self.inner.into_inner();
+        if poisoned {
+            Err(poison::PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+impl<T, R: lock_api::RawMutexTimed> Mutex<T, R> {
+    /// Like `lock()`, but gives up and returns `WouldBlock` after `timeout`.
+    #[track_caller]
+    pub fn try_lock_for(&self, timeout: Duration) -> TryLockResult<MutexGuard<'_, T, R>> {
+        match self.inner.try_lock_for(timeout) {
+            Some(inner) => Ok(self.guard(inner)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Like `lock()`, but gives up and returns `WouldBlock` once `deadline` has passed.
+    #[track_caller]
+    pub fn try_lock_until(&self, deadline: Instant) -> TryLockResult<MutexGuard<'_, T, R>> {
+        match self.inner.try_lock_until(deadline) {
+            Some(inner) => Ok(self.guard(inner)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::Ordering::Relaxed,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{MappedMutexGuard, Mutex, MutexGuard};
+    use crate::poison::TryLockError;
+
+    #[test]
+    fn try_lock() {
+        let m = Mutex::new(0);
+        let g = m.try_lock().unwrap();
+        assert!(matches!(m.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(g);
+        assert!(m.try_lock().is_ok());
+    }
+
+    #[test]
+    fn lock_timeout() {
+        let m = Mutex::new(0);
+        thread::scope(|s| {
+            let g = m.lock().unwrap();
+            s.spawn(|| {
+                let start = Instant::now();
+                assert!(m.try_lock_for(Duration::from_millis(50)).is_err());
+                assert!(start.elapsed() >= Duration::from_millis(50));
+
+                // The holder lets go well before this deadline.
+                *m.try_lock_until(Instant::now() + Duration::from_secs(10))
+                    .unwrap() += 1;
+            });
+            thread::sleep(Duration::from_millis(200));
+            drop(g);
+        });
+        assert_eq!(*m.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn map() {
+        let m = Mutex::new(Some(vec![1]));
+        let mut v = MutexGuard::map(m.lock().unwrap(), |o| o.as_mut().unwrap());
+        v.push(2);
+        let mut first = MappedMutexGuard::map(v, |v| &mut v[0]);
+        *first = 0;
+        drop(first);
+
+        let g = m.lock().unwrap();
+        assert_eq!(g.as_deref(), Some(&[0, 2][..]));
+        // Mapping to nothing gives the original guard back, still locked.
+        let mut g = MutexGuard::try_map(g, |_| None::<&mut i32>).err().unwrap();
+        assert!(m.try_lock().is_err());
+        *g = None;
+        drop(g);
+        assert!(m.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn unlock_fair() {
+        let m = Mutex::new(0);
+        thread::scope(|s| {
+            let g = m.lock().unwrap();
+            s.spawn(|| *m.lock().unwrap() += 1);
+            // Give the other thread time to go to sleep.
+            thread::sleep(Duration::from_millis(100));
+            g.unlock_fair();
+            // The lock now belongs to the other thread, even if it didn't run yet.
+            assert!(m.try_lock().is_err());
+        });
+        assert_eq!(*m.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn eventual_fairness() {
+        let m = Mutex::new(false).with_max_wait(Duration::from_millis(10));
+        thread::scope(|s| {
+            s.spawn(|| {
+                // Keep relocking right away, which would starve the other thread,
+                // until it finally gets a turn.
+                let mut g = m.lock().unwrap();
+                while !*g {
+                    drop(g);
+                    g = m.lock().unwrap();
+                }
+            });
+            thread::sleep(Duration::from_millis(10));
+            let start = Instant::now();
+            *m.lock().unwrap() = true;
+            // Generous, since a fair unlock still has to wait for us to be scheduled.
+            assert!(start.elapsed() < Duration::from_secs(1));
+        });
+        assert!(!m.inner.raw.starving.load(Relaxed));
+
+        // A starving waiter that gives up doesn't leave the lock starving.
+        let g = m.lock().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| assert!(m.try_lock_for(Duration::from_millis(30)).is_err()));
+        });
+        drop(g);
+        assert!(!m.inner.raw.starving.load(Relaxed));
+    }
+
+    #[test]
+    fn poison() {
+        let m = Mutex::new(0);
+        thread::scope(|s| {
+            let r = s.spawn(|| {
+                let mut g = m.lock().unwrap();
+                *g = 1;
+                panic!("oops");
+            });
+            assert!(r.join().is_err());
+        });
+        assert!(m.is_poisoned());
+
+        // The data is still reachable through the error.
+        let g = m.lock().err().unwrap().into_inner();
+        assert_eq!(*g, 1);
+        drop(g);
+        assert!(matches!(m.try_lock(), Err(TryLockError::Poisoned(_))));
+
+        m.clear_poison();
+        assert_eq!(*m.lock().unwrap(), 1);
+        assert_eq!(m.into_inner().unwrap(), 1);
+    }
 }
@@ -0,0 +1,290 @@
+//! A mutex using the Linux priority inheritance futex protocol.
+//!
+//! The lock word holds the kernel thread id of the owner, so when a thread
+//! has to wait, the kernel knows who to boost: a low priority owner runs with
+//! the priority of its highest priority waiter until it unlocks. With the plain
+//! futex protocol of `Mutex`, a medium priority thread could keep that owner
+//! from running, and so the high priority waiter too (priority inversion).
+//!
+//! `PiMutex` is `mutex::Mutex` on this algorithm, so it has the same guards
+//! and poisoning:
+//!
+//! ```
+//! use lock_learning::pi_mutex::{PiMutex, RawPiMutex};
+//!
+//! let m = PiMutex::from_raw(RawPiMutex::new(), 1);
+//! *m.lock().unwrap() += 1;
+//! assert_eq!(m.into_inner().unwrap(), 2);
+//! ```
+
+use std::{
+    cell::Cell,
+    sync::atomic::{
+        fence, AtomicU32,
+        Ordering::{Acquire, Relaxed, Release},
+    },
+    thread,
+    time::Instant,
+};
+
+use crate::{
+    futex,
+    lock_api::{self, RawMutex as _},
+    lockdep, mutex,
+    stats::{LockStats, Timer},
+};
+
+/// The lock part of `PiMutex`, without the data.
+pub struct RawPiMutex {
+    /// - 0: unlocked
+    /// - otherwise: the thread id of the owner, with `FUTEX_WAITERS`
+    ///   set by the kernel if other threads are waiting.
+    ///
+    /// Only 0 -> tid and tid -> 0 happen in user space,
+    /// everything else is left to `FUTEX_LOCK_PI` and `FUTEX_UNLOCK_PI`.
+    state: AtomicU32,
+    stats: LockStats,
+    lockdep: lockdep::Class,
+}
+
+/// Like `Mutex`, but threads waiting for the lock lend their priority to its owner.
+///
+/// Made for threads with real-time priorities. It's Linux only,
+/// and it doesn't spin: every contended lock and unlock is a syscall.
+pub type PiMutex<T> = mutex::Mutex<T, RawPiMutex>;
+pub type PiMutexGuard<'a, T> = mutex::MutexGuard<'a, T, RawPiMutex>;
+/// A `PiMutexGuard` made by `PiMutexGuard::map()`, pointing to a part of the data.
+pub type MappedPiMutexGuard<'a, T> = mutex::MappedMutexGuard<'a, T, RawPiMutex>;
+
+impl Default for RawPiMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RawPiMutex {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            stats: LockStats::new(),
+            lockdep: lockdep::Class::new(),
+        }
+    }
+
+    /// Returns `false` if `deadline` passed before we got the lock.
+    #[track_caller]
+    fn lock_until(&self, deadline: Option<Instant>) -> bool {
+        self.lockdep.check();
+        if self.try_lock() {
+            return true;
+        }
+        let timer = Timer::start();
+        self.stats.futex_wait();
+        // The kernel sets `FUTEX_WAITERS`, so the owner's unlock takes the slow path.
+        match futex::lock_pi(&self.state, deadline) {
+            Ok(true) => {}
+            Ok(false) => return false,
+            // The owner exited without unlocking (its guard was leaked),
+            // so the lock is never unlocked again, just like a leaked `Mutex`.
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ESRCH | libc::EOWNERDEAD)) => {
+                return sleep_until(deadline);
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EDEADLK) => {
+                panic!("PiMutex locked twice by the same thread")
+            }
+            Err(e) => panic!("FUTEX_LOCK_PI failed: {e}"),
+        }
+        // The kernel wrote our tid, make sure we see what the previous owner did.
+        fence(Acquire);
+        self.stats.contended();
+        self.stats.waited(timer);
+        self.stats.acquired();
+        self.lockdep.acquired();
+        true
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawPiMutex {
+    const INIT: Self = Self::new();
+
+    const NAME: &'static str = "PiMutex";
+
+    // The kernel needs the unlock to happen on the owning thread.
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock(&self) {
+        self.lock_until(None);
+    }
+
+    fn try_lock(&self) -> bool {
+        let locked = self.try_lock_quiet();
+        if locked {
+            self.stats.uncontended();
+            self.stats.acquired();
+            self.lockdep.acquired();
+        }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        self.lockdep.released();
+        self.stats.released();
+        self.unlock_quiet();
+    }
+
+    fn try_lock_quiet(&self) -> bool {
+        self.state
+            .compare_exchange(0, current_tid(), Acquire, Relaxed)
+            .is_ok()
+    }
+
+    unsafe fn unlock_quiet(&self) {
+        // Only possible without waiters, otherwise `FUTEX_WAITERS` is set.
+        if self
+            .state
+            .compare_exchange(current_tid(), 0, Release, Relaxed)
+            .is_err()
+        {
+            // The kernel hands the lock to the highest priority waiter,
+            // and drops the priority boost we may have gotten.
+            self.stats.futex_wake();
+            futex::unlock_pi(&self.state);
+        }
+    }
+}
+
+unsafe impl lock_api::RawMutexFair for RawPiMutex {
+    /// The same as `unlock()`: `FUTEX_UNLOCK_PI` always hands
+    /// the lock to a waiting thread, if any.
+    ///
+    /// Only here so code can switch between `Mutex` and `PiMutex`.
+    unsafe fn unlock_fair(&self) {
+        self.unlock();
+    }
+}
+
+unsafe impl lock_api::RawMutexTimed for RawPiMutex {
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        self.lock_until(Some(deadline))
+    }
+}
+
+impl<T> PiMutex<T> {
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::StatsSnapshot {
+        self.inner.raw.stats.snapshot()
+    }
+}
+
+/// Sleeps until `deadline`, or forever if there is none. Returns `false`.
+fn sleep_until(deadline: Option<Instant>) -> bool {
+    loop {
+        match deadline {
+            None => thread::park(),
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(d) if !d.is_zero() => thread::park_timeout(d),
+                _ => return false,
+            },
+        }
+    }
+}
+
+/// The kernel's id of the current thread, as it's stored in the lock word.
+fn current_tid() -> u32 {
+    thread_local!(static TID: Cell<u32> = const { Cell::new(0) });
+    TID.with(|tid| {
+        if tid.get() == 0 {
+            tid.set(unsafe { libc::gettid() } as u32);
+        }
+        tid.get()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::Ordering::Relaxed,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{current_tid, PiMutex, PiMutexGuard, RawPiMutex};
+    use crate::condition_variable::Condvar;
+
+    static LEAKED: PiMutex<i32> = PiMutex::from_raw(RawPiMutex::new(), 0);
+
+    #[test]
+    fn pi_mutex() {
+        let m = PiMutex::from_raw(RawPiMutex::new(), 0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *m.lock().unwrap() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(m.into_inner().unwrap(), 4000);
+    }
+
+    #[test]
+    fn owner_and_timeout() {
+        let m = PiMutex::from_raw(RawPiMutex::new(), vec![0]);
+        thread::scope(|s| {
+            let g = m.lock().unwrap();
+            // The kernel finds the owner through the lock word.
+            assert_eq!(m.inner.raw.state.load(Relaxed), current_tid());
+            s.spawn(|| {
+                let start = Instant::now();
+                assert!(m.try_lock().is_err());
+                assert!(m.try_lock_for(Duration::from_millis(50)).is_err());
+                assert!(start.elapsed() >= Duration::from_millis(50));
+
+                let mut v = PiMutexGuard::map(m.lock().unwrap(), |v| &mut v[0]);
+                *v += 1;
+            });
+            thread::sleep(Duration::from_millis(200));
+            drop(g);
+        });
+        assert_eq!(m.inner.raw.state.load(Relaxed), 0);
+        assert_eq!(m.lock().unwrap()[0], 1);
+    }
+
+    #[test]
+    fn owner_exited() {
+        // Not a scoped thread, so `join()` waits until it's really gone.
+        thread::spawn(|| std::mem::forget(LEAKED.lock().unwrap()))
+            .join()
+            .unwrap();
+        // The kernel won't wait for an owner that doesn't exist anymore,
+        // but the lock stays locked, like a `Mutex` with a leaked guard.
+        let start = Instant::now();
+        assert!(LEAKED.try_lock_for(Duration::from_millis(50)).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn condvar_and_poison() {
+        let m = PiMutex::from_raw(RawPiMutex::new(), false);
+        let cv = Condvar::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                *m.lock().unwrap() = true;
+                cv.notify_one();
+            });
+            let g = cv.wait_while(m.lock().unwrap(), |ready| !*ready).unwrap();
+            assert!(*g);
+        });
+
+        thread::scope(|s| {
+            let r = s.spawn(|| {
+                let _g = m.lock().unwrap();
+                panic!("oops");
+            });
+            assert!(r.join().is_err());
+        });
+        assert!(m.is_poisoned());
+        assert!(m.lock().is_err());
+    }
+}
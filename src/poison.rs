@@ -0,0 +1,137 @@
+use std::{
+    error::Error,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering::Relaxed},
+    thread,
+};
+
+/// A lock is poisoned when a thread panics while holding it exclusively.
+///
+/// The guard is still inside, so the data can be recovered with `into_inner()`.
+pub struct PoisonError<G> {
+    guard: G,
+}
+
+impl<G> PoisonError<G> {
+    pub fn new(guard: G) -> Self {
+        Self { guard }
+    }
+
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+
+    pub fn get_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+impl<G> fmt::Debug for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoisonError").finish_non_exhaustive()
+    }
+}
+
+impl<G> fmt::Display for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "poisoned lock: another task failed inside".fmt(f)
+    }
+}
+
+impl<G> Error for PoisonError<G> {}
+
+pub enum TryLockError<G> {
+    /// We got the lock, but it's poisoned.
+    Poisoned(PoisonError<G>),
+    /// The lock is held by someone else (or we timed out waiting for it).
+    WouldBlock,
+}
+
+impl<G> From<PoisonError<G>> for TryLockError<G> {
+    fn from(err: PoisonError<G>) -> Self {
+        Self::Poisoned(err)
+    }
+}
+
+impl<G> fmt::Debug for TryLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Poisoned(e) => e.fmt(f),
+            Self::WouldBlock => "WouldBlock".fmt(f),
+        }
+    }
+}
+
+impl<G> fmt::Display for TryLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Poisoned(e) => e.fmt(f),
+            Self::WouldBlock => "try_lock failed because the operation would block".fmt(f),
+        }
+    }
+}
+
+impl<G> Error for TryLockError<G> {}
+
+pub type LockResult<G> = Result<G, PoisonError<G>>;
+
+pub type TryLockResult<G> = Result<G, TryLockError<G>>;
+
+/// Poison state of one lock.
+pub(crate) struct Flag {
+    failed: AtomicBool,
+}
+
+/// Remembers whether the thread was already panicking when it took the lock,
+/// so a guard dropped during unwinding of an older panic doesn't poison.
+#[derive(Clone, Copy)]
+pub(crate) struct Guard {
+    panicking: bool,
+}
+
+impl Flag {
+    pub(crate) const fn new() -> Self {
+        Self {
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Called right after the lock is taken.
+    pub(crate) fn guard(&self) -> Result<Guard, PoisonError<Guard>> {
+        let guard = Guard {
+            panicking: thread::panicking(),
+        };
+        if self.get() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Called right before the lock is released.
+    pub(crate) fn done(&self, guard: &Guard) {
+        if !guard.panicking && thread::panicking() {
+            // The lock itself orders this with the next owner.
+            self.failed.store(true, Relaxed);
+        }
+    }
+
+    pub(crate) fn get(&self) -> bool {
+        self.failed.load(Relaxed)
+    }
+
+    pub(crate) fn clear(&self) {
+        self.failed.store(false, Relaxed)
+    }
+}
+
+pub(crate) fn map_result<T, U>(result: LockResult<T>, f: impl FnOnce(T) -> U) -> LockResult<U> {
+    match result {
+        Ok(t) => Ok(f(t)),
+        Err(e) => Err(PoisonError::new(f(e.into_inner()))),
+    }
+}
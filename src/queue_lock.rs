@@ -0,0 +1,345 @@
+//! Spin locks where every waiter spins on its own queue node, instead of all
+//! of them spinning on the same word like in `SpinLock` and `TicketLock`.
+//! Unlocking then only touches the cache line of the next waiter, so heavily
+//! contended locks don't slow down with every extra waiter. Both serve waiters
+//! in the order they arrived.
+//!
+//! In an MCS lock (Mellor-Crummey and Scott, 1991) `tail` points to the last
+//! waiter's node. A new waiter links itself behind it and spins on its own node,
+//! until the holder hands the lock over by clearing it.
+//!
+//! In a CLH lock (Craig, Landin and Hagersten) a waiter instead spins on the
+//! node of the one in front of it, which needs no links, but the holder can't
+//! take its node back: the next waiter is still looking at it. So the waiter
+//! takes over the node of the one in front when it gets the lock.
+//!
+//! A guard owns the node it got when locking. Nodes are boxed, so they stay put
+//! while the guard moves, and each thread keeps a spare one, so locking doesn't
+//! allocate every time.
+
+use std::{
+    cell::{Cell, UnsafeCell},
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    sync::atomic::{
+        AtomicBool, AtomicPtr,
+        Ordering::{AcqRel, Acquire, Relaxed, Release},
+    },
+};
+
+use crate::backoff::{Spin, SpinPolicy};
+
+struct Node {
+    /// Set while the lock is held or waited for through this node.
+    locked: AtomicBool,
+    /// MCS only: the waiter behind this one.
+    next: AtomicPtr<Node>,
+}
+
+thread_local! {
+    static SPARE: Cell<Option<Box<Node>>> = const { Cell::new(None) };
+}
+
+/// A node set to locked, from this thread's spare if it has one.
+fn new_node() -> NonNull<Node> {
+    let node = SPARE.try_with(Cell::take).ok().flatten();
+    let node = match node {
+        Some(node) => {
+            node.locked.store(true, Relaxed);
+            node.next.store(ptr::null_mut(), Relaxed);
+            node
+        }
+        None => Box::new(Node {
+            locked: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }),
+    };
+    NonNull::from(Box::leak(node))
+}
+
+/// Keeps `node` as this thread's spare.
+///
+/// # Safety
+///
+/// `node` must come from `new_node()`, and no other thread may still use it.
+unsafe fn recycle(node: NonNull<Node>) {
+    let node = Box::from_raw(node.as_ptr());
+    // If the thread is exiting, the node is just dropped.
+    let _ = SPARE.try_with(|spare| spare.set(Some(node)));
+}
+
+/// Waits until `node` isn't locked anymore.
+fn wait(node: &Node, spin: &dyn SpinPolicy) {
+    let mut attempt = 0u32;
+    // Acquire matches the Release in the unlock that cleared it.
+    while node.locked.load(Acquire) {
+        // We can't sleep, so when the policy gives up on spinning,
+        // at least let another thread (hopefully the holder) run.
+        if !spin.backoff(attempt) {
+            std::thread::yield_now();
+        }
+        attempt = attempt.saturating_add(1);
+    }
+}
+
+pub struct McsLock<T> {
+    /// The node of the last thread that holds or waits for the lock, or null.
+    tail: AtomicPtr<Node>,
+    spin: &'static dyn SpinPolicy,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for McsLock<T> {}
+
+pub struct McsGuard<'a, T> {
+    lock: &'a McsLock<T>,
+    node: NonNull<Node>,
+}
+
+unsafe impl<T: Sync> Sync for McsGuard<'_, T> {}
+
+impl<T> Deref for McsGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for McsGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for McsGuard<'_, T> {
+    fn drop(&mut self) {
+        let node = unsafe { self.node.as_ref() };
+        let mut next = node.next.load(Acquire);
+        if next.is_null() {
+            // Nobody behind us, unless one just swapped `tail` and is about to link in.
+            // Release matches the AcqRel swap in `lock()` of whoever comes next.
+            if self
+                .lock
+                .tail
+                .compare_exchange(self.node.as_ptr(), ptr::null_mut(), Release, Relaxed)
+                .is_ok()
+            {
+                unsafe { recycle(self.node) };
+                return;
+            }
+            let mut attempt = 0u32;
+            while next.is_null() {
+                if !self.lock.spin.backoff(attempt) {
+                    std::thread::yield_now();
+                }
+                attempt = attempt.saturating_add(1);
+                next = node.next.load(Acquire);
+            }
+        }
+        // Release matches the Acquire in `wait()`.
+        unsafe { (*next).locked.store(false, Release) };
+        // The next waiter was done with our node once it linked in.
+        unsafe { recycle(self.node) };
+    }
+}
+
+impl<T> McsLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            spin: &Spin { limit: 100 },
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Replaces the default policy of spinning 100 times before yielding.
+    pub const fn with_spin_policy(mut self, spin: &'static dyn SpinPolicy) -> Self {
+        self.spin = spin;
+        self
+    }
+
+    pub fn lock(&self) -> McsGuard<'_, T> {
+        let node = new_node();
+        // Release, so the one in front sees our node initialized,
+        // Acquire, to get the lock from an unlock that left `tail` null.
+        let prev = self.tail.swap(node.as_ptr(), AcqRel);
+        if !prev.is_null() {
+            // Safety: The one in front keeps its node until it sees us here.
+            unsafe { (*prev).next.store(node.as_ptr(), Release) };
+            wait(unsafe { node.as_ref() }, self.spin);
+        }
+        McsGuard { lock: self, node }
+    }
+
+    /// Returns `None` instead of waiting if the lock is held.
+    pub fn try_lock(&self) -> Option<McsGuard<'_, T>> {
+        let node = new_node();
+        match self
+            .tail
+            .compare_exchange(ptr::null_mut(), node.as_ptr(), AcqRel, Relaxed)
+        {
+            Ok(_) => Some(McsGuard { lock: self, node }),
+            Err(_) => {
+                unsafe { recycle(node) };
+                None
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+pub struct ClhLock<T> {
+    /// The node the next thread to arrive waits on, or null if no thread ever
+    /// locked. It's only freed when the lock is, since the node stays in the
+    /// queue after its thread unlocked, until the next thread takes it over.
+    tail: AtomicPtr<Node>,
+    spin: &'static dyn SpinPolicy,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for ClhLock<T> {}
+
+impl<T> Drop for ClhLock<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        if !tail.is_null() {
+            // Safety: Nobody is waiting on it, since nobody holds a guard.
+            drop(unsafe { Box::from_raw(tail) });
+        }
+    }
+}
+
+pub struct ClhGuard<'a, T> {
+    lock: &'a ClhLock<T>,
+    /// The node we put in the queue, which the next thread waits on.
+    node: NonNull<Node>,
+    /// The node we waited on, which is ours now.
+    prev: Option<NonNull<Node>>,
+}
+
+unsafe impl<T: Sync> Sync for ClhGuard<'_, T> {}
+
+impl<T> Deref for ClhGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for ClhGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for ClhGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release matches the Acquire in `wait()`. After this,
+        // `node` belongs to whoever comes next.
+        unsafe { self.node.as_ref().locked.store(false, Release) };
+        if let Some(prev) = self.prev {
+            unsafe { recycle(prev) };
+        }
+    }
+}
+
+impl<T> ClhLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            spin: &Spin { limit: 100 },
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Replaces the default policy of spinning 100 times before yielding.
+    pub const fn with_spin_policy(mut self, spin: &'static dyn SpinPolicy) -> Self {
+        self.spin = spin;
+        self
+    }
+
+    pub fn lock(&self) -> ClhGuard<'_, T> {
+        let node = new_node();
+        // Release, so the one behind us sees our node initialized,
+        // Acquire, so we see the node in front initialized.
+        let prev = NonNull::new(self.tail.swap(node.as_ptr(), AcqRel));
+        if let Some(prev) = prev {
+            // Safety: Nobody takes over `prev` but us.
+            wait(unsafe { prev.as_ref() }, self.spin);
+        }
+        ClhGuard {
+            lock: self,
+            node,
+            prev,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        let this = std::mem::ManuallyDrop::new(self);
+        let tail = this.tail.load(Relaxed);
+        if !tail.is_null() {
+            drop(unsafe { Box::from_raw(tail) });
+        }
+        // Safety: `this` is never used or dropped again.
+        unsafe { ptr::read(&this.value) }.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::{ClhLock, McsLock};
+
+    #[test]
+    fn mcs_lock() {
+        let lock = McsLock::new(0);
+        let g = lock.lock();
+        assert!(lock.try_lock().is_none());
+        drop(g);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *lock.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.try_lock().unwrap(), 4000);
+        assert_eq!(lock.into_inner(), 4000);
+    }
+
+    #[test]
+    fn clh_lock() {
+        let lock = ClhLock::new(Vec::new());
+        thread::scope(|s| {
+            for i in 0..4 {
+                let lock = &lock;
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        lock.lock().push(i);
+                    }
+                });
+            }
+        });
+        let mut v = lock.into_inner();
+        v.sort();
+        assert_eq!(v.len(), 4000);
+        assert!(v
+            .chunks(1000)
+            .enumerate()
+            .all(|(i, c)| c.iter().all(|&x| x == i)));
+    }
+}
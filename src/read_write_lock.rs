@@ -1,114 +1,213 @@
 use std::{
-    assert_ne,
-    cell::UnsafeCell,
+    mem::ManuallyDrop,
     ops::{Deref, DerefMut},
+    ptr,
     sync::atomic::{
-        AtomicU32,
-        Ordering::{Acquire, Relaxed, Release},
+        AtomicBool, AtomicU32,
+        Ordering::{Acquire, Relaxed, Release, SeqCst},
     },
+    time::{Duration, Instant},
 };
 
 use atomic_wait::{wait, wake_all, wake_one};
 
-pub struct RwLock<T> {
+use crate::{
+    backoff::{NoSpin, SpinPolicy},
+    condition_variable::CondvarGuard,
+    futex, lock_api, lockdep,
+    poison::{self, LockResult, PoisonError, TryLockError, TryLockResult},
+    stats::{LockStats, Timer},
+};
+
+/// Who goes first when readers and writers are both waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preference {
+    /// Readers only wait while a writer holds the lock, not while one is
+    /// waiting for it. Readers never wait long, but a steady stream of
+    /// them can keep writers out forever.
+    Readers,
+    /// Readers also wait while a writer is waiting,
+    /// so writers can't be kept out, but readers can.
+    #[default]
+    Writers,
+    /// Like `Writers`, but the readers that had to wait for a writer
+    /// all get in before the next writer does, so phases of readers and
+    /// writers take turns, and neither can be kept out.
+    PhaseFair,
+}
+
+/// The lock part of `RwLock`, without the data.
+pub struct RawRwLock {
     /// The number of read locks times two, plus one if has writer waiting,
     /// u32::MAX if write locked.
     ///
     /// This means that readers may acquire the lock when state is even,
-    /// but need to block when odd.
+    /// but need to block when odd (unless `preference` says otherwise).
     state: AtomicU32,
     /// Incremented to wake up writers.
     writer_wake_counter: AtomicU32,
-    value: UnsafeCell<T>,
-}
-
-unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
-
-pub struct ReadGuard<'a, T> {
-    rwlock: &'a RwLock<T>,
+    /// The number of readers asleep on `state`, and of writers asleep on `writer_wake_counter`,
+    /// so unlocking a write lock doesn't need a syscall when nobody is asleep.
+    sleeping_readers: AtomicU32,
+    sleeping_writers: AtomicU32,
+    /// Who may hold an upgradable read lock, on top of a normal read lock in `state`.
+    /// 0: nobody, 1: somebody, 2: somebody and others are waiting for it.
+    upgradable: AtomicU32,
+    /// Set while the upgradable reader waits in `upgrade()` for the other readers to leave.
+    upgrading: AtomicBool,
+    preference: Preference,
+    /// Only for `PhaseFair`: the number of finished write locks, and the number of readers
+    /// waiting that started waiting when it was even and odd. Readers that started
+    /// in an earlier phase go before the next writer.
+    write_phase: AtomicU32,
+    waiting_readers: [AtomicU32; 2],
+    /// How long to spin before going to sleep.
+    spin: &'static dyn SpinPolicy,
+    stats: LockStats,
+    lockdep: lockdep::Class,
 }
 
-impl<T> Deref for ReadGuard<'_, T> {
-    type Target = T;
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.rwlock.value.get() }
+impl Default for RawRwLock {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl<T> Drop for ReadGuard<'_, T> {
-    fn drop(&mut self) {
-        // state now is 1, means one writer is waiting
-        if self.rwlock.state.fetch_sub(2, Release) == 3 {
-            self.rwlock.writer_wake_counter.fetch_add(1, Release);
-            wake_one(&self.rwlock.writer_wake_counter);
+impl RawRwLock {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writer_wake_counter: AtomicU32::new(0),
+            sleeping_readers: AtomicU32::new(0),
+            sleeping_writers: AtomicU32::new(0),
+            upgradable: AtomicU32::new(0),
+            upgrading: AtomicBool::new(false),
+            preference: Preference::Writers,
+            write_phase: AtomicU32::new(0),
+            waiting_readers: [AtomicU32::new(0), AtomicU32::new(0)],
+            spin: &NoSpin,
+            stats: LockStats::new(),
+            lockdep: lockdep::Class::new(),
         }
     }
-}
-
-pub struct WriteGuard<'a, T> {
-    rwlock: &'a RwLock<T>,
-}
-
-impl<T> Deref for WriteGuard<'_, T> {
-    type Target = T;
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.rwlock.value.get() }
-    }
-}
 
-impl<T> DerefMut for WriteGuard<'_, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.rwlock.value.get() }
+    fn record_acquire(&self, spins: u32, blocked: bool, timer: Timer) {
+        if spins == 0 && !blocked {
+            self.stats.uncontended();
+        } else {
+            self.stats.contended();
+            self.stats.spins(spins);
+            self.stats.waited(timer);
+        }
     }
-}
 
-impl<T> Drop for WriteGuard<'_, T> {
-    fn drop(&mut self) {
-        self.rwlock.state.store(0, Release);
-        self.rwlock.writer_wake_counter.fetch_add(1, Release);
-        wake_one(&self.rwlock.writer_wake_counter);
-        wake_all(&self.rwlock.state);
+    /// Whether a reader may come in while `state` is `s`,
+    /// if it has been waiting since write phase `waiting_since`.
+    fn reader_may_enter(&self, s: u32, waiting_since: Option<u32>) -> bool {
+        match self.preference {
+            Preference::Readers => s != u32::MAX,
+            Preference::Writers => s % 2 == 0,
+            Preference::PhaseFair => {
+                s % 2 == 0
+                    || s != u32::MAX
+                        && waiting_since.is_some_and(|p| p != self.write_phase.load(Relaxed))
+            }
+        }
     }
-}
 
-impl<T> RwLock<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            state: AtomicU32::new(0),
-            writer_wake_counter: AtomicU32::new(0),
-            value: UnsafeCell::new(value),
+    /// Whether a `PhaseFair` writer has to let readers from an earlier phase in first.
+    fn readers_first(&self) -> bool {
+        self.preference == Preference::PhaseFair && {
+            let previous = self.write_phase.load(Relaxed).wrapping_sub(1);
+            self.waiting_readers[previous as usize % 2].load(Acquire) > 0
         }
     }
 
-    pub fn read(&self) -> ReadGuard<T> {
+    /// Returns `false` if `deadline` passed before we got the lock.
+    #[track_caller]
+    fn lock_shared_until(&self, deadline: Option<Instant>) -> bool {
+        let timer = Timer::start();
+        let mut blocked = false;
         let mut s = self.state.load(Relaxed);
+        let mut attempt = 0u32;
+        // The write phase in which a `PhaseFair` reader started to wait.
+        let mut waiting_since = None;
+        let stop_waiting = |waiting_since: Option<u32>| {
+            if let Some(p) = waiting_since {
+                self.waiting_readers[p as usize % 2].fetch_sub(1, Release);
+            }
+        };
         loop {
-            // Even: no writer waiting
-            if s % 2 == 0 {
-                assert_ne!(s, u32::MAX - 2, "too many readers");
+            if self.reader_may_enter(s, waiting_since) {
+                assert!(s < u32::MAX - 3, "too many readers");
                 match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
-                    Ok(_) => return ReadGuard { rwlock: self },
-                    Err(e) => s = e,
+                    Ok(_) => {
+                        // After taking the lock, so a writer that sees
+                        // we're no longer waiting also sees our lock.
+                        stop_waiting(waiting_since);
+                        self.lockdep.acquired();
+                        self.record_acquire(attempt, blocked, timer);
+                        return true;
+                    }
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
                 }
             }
 
-            // Odd: has writer waiting or write-locked
-            // INFO: u32::MAX is odd too
-            if s % 2 == 1 {
-                wait(&self.state, s);
-                s = self.state.load(Relaxed);
+            // Has writer waiting or write-locked
+            if self.spin.backoff(attempt) {
+                attempt = attempt.saturating_add(1);
+            } else {
+                if self.preference == Preference::PhaseFair && waiting_since.is_none() {
+                    let p = self.write_phase.load(Relaxed);
+                    self.waiting_readers[p as usize % 2].fetch_add(1, Relaxed);
+                    waiting_since = Some(p);
+                }
+                blocked = true;
+                self.stats.futex_wait();
+                // SeqCst, see `wake_after_exclusive()`.
+                self.sleeping_readers.fetch_add(1, SeqCst);
+                let woken = futex::wait_until(&self.state, s, deadline);
+                self.sleeping_readers.fetch_sub(1, Relaxed);
+                if !woken {
+                    // Readers don't leave anything behind in `state`, but a `PhaseFair`
+                    // writer might be asleep until we have come in and left again,
+                    // see `readers_first()`, so wake it if we were the last one.
+                    if let Some(p) = waiting_since {
+                        if self.waiting_readers[p as usize % 2].fetch_sub(1, Release) == 1 {
+                            self.writer_wake_counter.fetch_add(1, Release);
+                            self.stats.futex_wake();
+                            wake_one(&self.writer_wake_counter);
+                        }
+                    }
+                    return false;
+                }
             }
+            // Acquire, to see the `write_phase` of the writer that unlocked.
+            s = self.state.load(Acquire);
         }
     }
 
-    pub fn write(&self) -> WriteGuard<T> {
+    /// Returns `false` if `deadline` passed before we got the lock.
+    #[track_caller]
+    fn lock_exclusive_until(&self, deadline: Option<Instant>) -> bool {
+        let timer = Timer::start();
+        let mut blocked = false;
         let mut s = self.state.load(Relaxed);
+        let mut attempt = 0u32;
         loop {
             // Try to lock if unlocked,
             // don't care whether there is a writer is waiting
-            if s <= 1 {
+            if s <= 1 && !self.readers_first() {
                 match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
-                    Ok(_) => return WriteGuard { rwlock: self },
+                    Ok(_) => {
+                        self.lockdep.acquired();
+                        self.record_acquire(attempt, blocked, timer);
+                        self.stats.acquired();
+                        return true;
+                    }
                     Err(e) => {
                         s = e;
                         continue;
@@ -125,12 +224,941 @@ impl<T> RwLock<T> {
                 }
             }
 
+            // Spin a bit before going to sleep
+            if self.spin.backoff(attempt) {
+                attempt = attempt.saturating_add(1);
+                s = self.state.load(Relaxed);
+                continue;
+            }
+
             // And wait
             let w = self.writer_wake_counter.load(Acquire);
-            if self.state.load(Relaxed) >= 2 {
-                wait(&self.writer_wake_counter, w);
+            // SeqCst, see `wake_after_exclusive()`.
+            self.sleeping_writers.fetch_add(1, SeqCst);
+            s = self.state.load(SeqCst);
+            // Readers going first wake us when the last of them leaves,
+            // since they see our odd bit.
+            let woken = if s >= 2 || self.readers_first() {
+                blocked = true;
+                self.stats.futex_wait();
+                let woken = futex::wait_until(&self.writer_wake_counter, w, deadline);
+                s = self.state.load(Relaxed);
+                woken
+            } else {
+                true
+            };
+            self.sleeping_writers.fetch_sub(1, Relaxed);
+            if !woken {
+                self.stop_blocking_readers();
+                return false;
+            }
+        }
+    }
+
+    /// Called by a writer that gives up waiting. It might be the one that set
+    /// the odd bit, and then nobody would ever clear it, so readers would block
+    /// forever. It can't tell whether other writers are still waiting,
+    /// so it clears the bit anyway and wakes them all to set it again.
+    fn stop_blocking_readers(&self) {
+        let mut s = self.state.load(Relaxed);
+        while s % 2 == 1 && s != u32::MAX {
+            match self.state.compare_exchange(s, s - 1, Relaxed, Relaxed) {
+                Ok(_) => {
+                    self.writer_wake_counter.fetch_add(1, Release);
+                    self.stats.futex_wake();
+                    wake_all(&self.writer_wake_counter);
+                    self.stats.futex_wake();
+                    wake_all(&self.state);
+                    return;
+                }
+                Err(e) => s = e,
+            }
+        }
+    }
+
+    fn lock_upgradable_token(&self) {
+        if self
+            .upgradable
+            .compare_exchange(0, 1, Acquire, Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+        while self.upgradable.swap(2, Acquire) != 0 {
+            self.stats.futex_wait();
+            wait(&self.upgradable, 2);
+        }
+    }
+
+    fn unlock_upgradable_token(&self) {
+        if self.upgradable.swap(0, Release) == 2 {
+            self.stats.futex_wake();
+            wake_one(&self.upgradable);
+        }
+    }
+
+    /// Wakes the writers, and the readers stopped by a waiting writer,
+    /// now that `state` is `s` instead of write locked.
+    ///
+    /// Only if any are asleep, so an uncontended unlock doesn't need a syscall.
+    /// A thread going to sleep counts itself before it checks `state`, and we
+    /// check the counts after changing `state`, all SeqCst, so either it sees
+    /// the new state and doesn't sleep, or we see it and wake it.
+    fn wake_after_exclusive(&self, s: u32) {
+        if self.preference == Preference::PhaseFair {
+            self.write_phase.fetch_add(1, Relaxed);
+        }
+        self.state.store(s, SeqCst);
+        if self.sleeping_writers.load(SeqCst) > 0 {
+            self.writer_wake_counter.fetch_add(1, Release);
+            self.stats.futex_wake();
+            wake_one(&self.writer_wake_counter);
+        }
+        if self.sleeping_readers.load(SeqCst) > 0 {
+            self.stats.futex_wake();
+            wake_all(&self.state);
+        }
+    }
+}
+
+unsafe impl lock_api::RawRwLock for RawRwLock {
+    const INIT: Self = Self::new();
+
+    // Lockdep tracks the held locks per thread.
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock_shared(&self) {
+        self.lockdep.check();
+        self.lock_shared_until(None);
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while self.reader_may_enter(s, None) {
+            assert!(s < u32::MAX - 3, "too many readers");
+            match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
+                Ok(_) => {
+                    self.stats.uncontended();
+                    self.lockdep.acquired();
+                    return true;
+                }
+                Err(e) => s = e,
+            }
+        }
+        false
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.lockdep.released();
+        // SeqCst, together with `upgrade()`: either we see `upgrading`,
+        // or the upgrading thread sees that we left.
+        match self.state.fetch_sub(2, SeqCst) {
+            // state now is 1, means one writer is waiting
+            3 => {
+                self.writer_wake_counter.fetch_add(1, Release);
+                self.stats.futex_wake();
+                wake_one(&self.writer_wake_counter);
+            }
+            // state now is 3, and if somebody is upgrading, that's their own read lock.
+            // Writers wait on the same counter, so wake them all to be sure to get it.
+            5 if self.upgrading.load(SeqCst) => {
+                self.writer_wake_counter.fetch_add(1, Release);
+                self.stats.futex_wake();
+                wake_all(&self.writer_wake_counter);
+            }
+            _ => {}
+        }
+    }
+
+    fn lock_exclusive(&self) {
+        self.lockdep.check();
+        self.lock_exclusive_until(None);
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        if self.readers_first() {
+            return false;
+        }
+        let mut s = self.state.load(Relaxed);
+        while s <= 1 {
+            match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
+                Ok(_) => {
+                    self.stats.uncontended();
+                    self.stats.acquired();
+                    self.lockdep.acquired();
+                    return true;
+                }
+                Err(e) => s = e,
+            }
+        }
+        false
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.lockdep.released();
+        self.stats.released();
+        self.wake_after_exclusive(0);
+    }
+}
+
+unsafe impl lock_api::RawRwLockTimed for RawRwLock {
+    fn try_lock_shared_until(&self, deadline: Instant) -> bool {
+        self.lockdep.check();
+        self.lock_shared_until(Some(deadline))
+    }
+
+    fn try_lock_exclusive_until(&self, deadline: Instant) -> bool {
+        self.lockdep.check();
+        self.lock_exclusive_until(Some(deadline))
+    }
+}
+
+/// The upgradable read lock is a normal read lock in `state`, plus the `upgradable`
+/// token that only one thread can have. That thread doesn't need to stop writers:
+/// a writer can't get in while it holds its read lock anyway.
+unsafe impl lock_api::RawRwLockUpgrade for RawRwLock {
+    fn lock_upgradable(&self) {
+        self.lock_upgradable_token();
+        lock_api::RawRwLock::lock_shared(self);
+    }
+
+    fn try_lock_upgradable(&self) -> bool {
+        if self
+            .upgradable
+            .compare_exchange(0, 1, Acquire, Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        if !lock_api::RawRwLock::try_lock_shared(self) {
+            self.unlock_upgradable_token();
+            return false;
+        }
+        true
+    }
+
+    unsafe fn unlock_upgradable(&self) {
+        lock_api::RawRwLock::unlock_shared(self);
+        self.unlock_upgradable_token();
+    }
+
+    unsafe fn upgrade(&self) {
+        let timer = Timer::start();
+        let mut blocked = false;
+        let mut s = self.state.load(Relaxed);
+        let mut attempt = 0u32;
+        loop {
+            // Only our own read lock left, maybe with a writer waiting behind it.
+            if s <= 3 {
+                match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
+                    Ok(_) => break,
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+
+            // Block new readers, like a writer.
+            if s % 2 == 0 {
+                if let Err(e) = self.state.compare_exchange(s, s + 1, Relaxed, Relaxed) {
+                    s = e;
+                    continue;
+                }
+            }
+
+            if self.spin.backoff(attempt) {
+                attempt = attempt.saturating_add(1);
                 s = self.state.load(Relaxed);
+                continue;
+            }
+
+            // The last other reader wakes us when it sees this, see `unlock_shared()`.
+            self.upgrading.store(true, SeqCst);
+            let w = self.writer_wake_counter.load(Acquire);
+            self.sleeping_writers.fetch_add(1, SeqCst);
+            if self.state.load(SeqCst) > 3 {
+                blocked = true;
+                self.stats.futex_wait();
+                wait(&self.writer_wake_counter, w);
+            }
+            self.sleeping_writers.fetch_sub(1, Relaxed);
+            s = self.state.load(Relaxed);
+        }
+        self.upgrading.store(false, Relaxed);
+        self.record_acquire(attempt, blocked, timer);
+        self.stats.acquired();
+        // The write lock keeps everyone else out, so the token can go.
+        self.unlock_upgradable_token();
+    }
+
+    unsafe fn try_upgrade(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while s <= 3 {
+            match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
+                Ok(_) => {
+                    self.stats.uncontended();
+                    self.stats.acquired();
+                    self.unlock_upgradable_token();
+                    return true;
+                }
+                Err(e) => s = e,
             }
         }
+        false
+    }
+}
+
+unsafe impl lock_api::RawRwLockDowngrade for RawRwLock {
+    unsafe fn downgrade(&self) {
+        self.stats.released();
+        // A writer that was waiting has to set its bit again, so wake it too.
+        self.wake_after_exclusive(2);
+    }
+}
+
+/// A `lock_api::RwLock` on the futex lock, with poisoning like `std::sync::RwLock`.
+///
+/// `R` is `RawRwLock` unless another lock algorithm is plugged in with `from_raw()`,
+/// like `membarrier::RawRwLock`.
+pub struct RwLock<T, R: lock_api::RawRwLock = RawRwLock> {
+    inner: lock_api::RwLock<R, T>,
+    /// Only writers poison the lock, like `std::sync::RwLock`.
+    poison: poison::Flag,
+}
+
+/// Readers can't poison, so they use the plain guards.
+pub type ReadGuard<'a, T, R = RawRwLock> = lock_api::ReadGuard<'a, R, T>;
+/// A `ReadGuard` made by `ReadGuard::map()`, pointing to a part of the data.
+pub type MappedReadGuard<'a, T, R = RawRwLock> = lock_api::MappedReadGuard<'a, R, T>;
+
+/// A read lock that can be upgraded to a write lock, see `RwLock::upgradable_read()`.
+pub struct UpgradableReadGuard<'a, T, R: lock_api::RawRwLockUpgrade = RawRwLock> {
+    inner: lock_api::UpgradableReadGuard<'a, R, T>,
+    poison_flag: &'a poison::Flag,
+}
+
+impl<T, R: lock_api::RawRwLockUpgrade> Deref for UpgradableReadGuard<'_, T, R> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<'a, T, R: lock_api::RawRwLockUpgrade> UpgradableReadGuard<'a, T, R> {
+    /// Waits for the other readers to leave, and turns this into a write lock.
+    ///
+    /// Nobody else can write in between, so what was read is still up to date.
+    /// Poisoning was already reported by `upgradable_read()`, so it isn't here.
+    pub fn upgrade(s: Self) -> WriteGuard<'a, T, R> {
+        Self::write_guard(
+            s.poison_flag,
+            lock_api::UpgradableReadGuard::upgrade(s.inner),
+        )
+    }
+
+    /// Like `upgrade()`, but gives the guard back if there are other readers.
+    pub fn try_upgrade(s: Self) -> Result<WriteGuard<'a, T, R>, Self> {
+        match lock_api::UpgradableReadGuard::try_upgrade(s.inner) {
+            Ok(inner) => Ok(Self::write_guard(s.poison_flag, inner)),
+            Err(inner) => Err(Self {
+                inner,
+                poison_flag: s.poison_flag,
+            }),
+        }
+    }
+
+    fn write_guard(
+        poison_flag: &'a poison::Flag,
+        inner: lock_api::WriteGuard<'a, R, T>,
+    ) -> WriteGuard<'a, T, R> {
+        WriteGuard {
+            inner,
+            poison_flag,
+            poison: poison_flag.guard().unwrap_or_else(PoisonError::into_inner),
+        }
+    }
+}
+
+pub struct WriteGuard<'a, T, R: lock_api::RawRwLock = RawRwLock> {
+    /// Dropped after `Drop::drop()`, so the poison flag is set before unlocking.
+    inner: lock_api::WriteGuard<'a, R, T>,
+    poison_flag: &'a poison::Flag,
+    poison: poison::Guard,
+}
+
+impl<T, R: lock_api::RawRwLock> Deref for WriteGuard<'_, T, R> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T, R: lock_api::RawRwLock> DerefMut for WriteGuard<'_, T, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<T, R: lock_api::RawRwLock> Drop for WriteGuard<'_, T, R> {
+    fn drop(&mut self) {
+        self.poison_flag.done(&self.poison);
+    }
+}
+
+impl<'a, T, R: lock_api::RawRwLock> WriteGuard<'a, T, R> {
+    /// Narrows the guard down to a part of the data while keeping it write locked.
+    pub fn map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedWriteGuard<'a, U, R> {
+        // `orig` stays alive while `f` runs, so a panic in `f` unlocks (and poisons).
+        let value: *mut U = f(&mut orig);
+        let (inner, poison_flag, poison) = orig.into_parts();
+        MappedWriteGuard {
+            inner: lock_api::WriteGuard::map(inner, |_| unsafe { &mut *value }),
+            poison_flag,
+            poison,
+        }
+    }
+
+    /// Like `map()`, but gives the original guard back if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedWriteGuard<'a, U, R>, Self> {
+        let value: *mut U = match f(&mut orig) {
+            Some(value) => value,
+            None => return Err(orig),
+        };
+        let (inner, poison_flag, poison) = orig.into_parts();
+        Ok(MappedWriteGuard {
+            inner: lock_api::WriteGuard::map(inner, |_| unsafe { &mut *value }),
+            poison_flag,
+            poison,
+        })
+    }
+
+    /// Takes the guard apart without unlocking or touching the poison flag.
+    fn into_parts(
+        self,
+    ) -> (
+        lock_api::WriteGuard<'a, R, T>,
+        &'a poison::Flag,
+        poison::Guard,
+    ) {
+        let guard = ManuallyDrop::new(self);
+        // Safety: `guard` is never used or dropped again.
+        let inner = unsafe { ptr::read(&guard.inner) };
+        (inner, guard.poison_flag, guard.poison)
+    }
+}
+
+impl<'a, T, R: lock_api::RawRwLockDowngrade> WriteGuard<'a, T, R> {
+    /// Turns this into a read lock, letting other readers in,
+    /// but no writer, so what was written is still there.
+    pub fn downgrade(s: Self) -> ReadGuard<'a, T, R> {
+        let (inner, poison_flag, poison) = s.into_parts();
+        poison_flag.done(&poison);
+        lock_api::WriteGuard::downgrade(inner)
+    }
+}
+
+/// A `WriteGuard` made by `WriteGuard::map()`, pointing to a part of the data.
+pub struct MappedWriteGuard<'a, T: ?Sized, R: lock_api::RawRwLock = RawRwLock> {
+    inner: lock_api::MappedWriteGuard<'a, R, T>,
+    poison_flag: &'a poison::Flag,
+    poison: poison::Guard,
+}
+
+impl<T: ?Sized, R: lock_api::RawRwLock> Deref for MappedWriteGuard<'_, T, R> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized, R: lock_api::RawRwLock> DerefMut for MappedWriteGuard<'_, T, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<T: ?Sized, R: lock_api::RawRwLock> Drop for MappedWriteGuard<'_, T, R> {
+    fn drop(&mut self) {
+        self.poison_flag.done(&self.poison);
+    }
+}
+
+impl<'a, T: ?Sized, R: lock_api::RawRwLock> MappedWriteGuard<'a, T, R> {
+    /// Narrows the guard down further, see `WriteGuard::map()`.
+    pub fn map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedWriteGuard<'a, U, R> {
+        let value: *mut U = f(&mut orig);
+        let (inner, poison_flag, poison) = orig.into_parts();
+        MappedWriteGuard {
+            inner: lock_api::MappedWriteGuard::map(inner, |_| unsafe { &mut *value }),
+            poison_flag,
+            poison,
+        }
+    }
+
+    /// Like `map()`, but gives the original guard back if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(
+        mut orig: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedWriteGuard<'a, U, R>, Self> {
+        let value: *mut U = match f(&mut orig) {
+            Some(value) => value,
+            None => return Err(orig),
+        };
+        let (inner, poison_flag, poison) = orig.into_parts();
+        Ok(MappedWriteGuard {
+            inner: lock_api::MappedWriteGuard::map(inner, |_| unsafe { &mut *value }),
+            poison_flag,
+            poison,
+        })
+    }
+
+    /// Takes the guard apart without unlocking or touching the poison flag.
+    fn into_parts(
+        self,
+    ) -> (
+        lock_api::MappedWriteGuard<'a, R, T>,
+        &'a poison::Flag,
+        poison::Guard,
+    ) {
+        let guard = ManuallyDrop::new(self);
+        // Safety: `guard` is never used or dropped again.
+        let inner = unsafe { ptr::read(&guard.inner) };
+        (inner, guard.poison_flag, guard.poison)
+    }
+}
+
+unsafe impl<T, R: lock_api::RawRwLockUpgrade> CondvarGuard for UpgradableReadGuard<'_, T, R> {
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        self.inner.unlocked(f)
+    }
+}
+
+unsafe impl<T, R: lock_api::RawRwLock> CondvarGuard for WriteGuard<'_, T, R> {
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        self.inner.unlocked(f)
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.poison_flag.get()
+    }
+}
+
+unsafe impl<T: ?Sized, R: lock_api::RawRwLock> CondvarGuard for MappedWriteGuard<'_, T, R> {
+    fn unlocked<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        self.inner.unlocked(f)
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.poison_flag.get()
+    }
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: lock_api::RwLock::new(value),
+            poison: poison::Flag::new(),
+        }
+    }
+
+    /// Replaces the default policy of going to sleep right away.
+    pub const fn with_spin_policy(mut self, spin: &'static dyn SpinPolicy) -> Self {
+        self.inner.raw.spin = spin;
+        self
+    }
+
+    /// Replaces the default of `Preference::Writers`.
+    pub const fn with_preference(mut self, preference: Preference) -> Self {
+        self.inner.raw.preference = preference;
+        self
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::StatsSnapshot {
+        self.inner.raw.stats.snapshot()
+    }
+}
+
+impl<T, R: lock_api::RawRwLock> RwLock<T, R> {
+    /// For another lock algorithm than `RawRwLock`, or one that was configured.
+    pub const fn from_raw(raw: R, value: T) -> Self {
+        Self {
+            inner: lock_api::RwLock::from_raw(raw, value),
+            poison: poison::Flag::new(),
+        }
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> LockResult<ReadGuard<'_, T, R>> {
+        self.read_guard(self.inner.read())
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> LockResult<WriteGuard<'_, T, R>> {
+        self.write_guard(self.inner.write())
+    }
+
+    /// Returns `WouldBlock` instead of blocking if a writer holds or waits for the lock.
+    #[track_caller]
+    pub fn try_read(&self) -> TryLockResult<ReadGuard<'_, T, R>> {
+        match self.inner.try_read() {
+            Some(inner) => Ok(self.read_guard(inner)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Returns `WouldBlock` instead of blocking if the lock is held.
+    #[track_caller]
+    pub fn try_write(&self) -> TryLockResult<WriteGuard<'_, T, R>> {
+        match self.inner.try_write() {
+            Some(inner) => Ok(self.write_guard(inner)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    fn read_guard<'a>(&'a self, guard: ReadGuard<'a, T, R>) -> LockResult<ReadGuard<'a, T, R>> {
+        if self.poison.get() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    fn write_guard<'a>(
+        &'a self,
+        inner: lock_api::WriteGuard<'a, R, T>,
+    ) -> LockResult<WriteGuard<'a, T, R>> {
+        poison::map_result(self.poison.guard(), |poison| WriteGuard {
+            inner,
+            poison_flag: &self.poison,
+            poison,
+        })
+    }
+
+    /// Whether a thread panicked while holding the write lock.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Call this after the data has been brought back into a consistent state.
+    pub fn clear_poison(&self) {
+        self.poison.clear()
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let value = self.inner.get_mut();
+        if self.poison.get() {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        let value = self.inner.into_inner();
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+impl<T, R: lock_api::RawRwLockTimed> RwLock<T, R> {
+    /// Like `read()`, but gives up and returns `WouldBlock` after `timeout`.
+    #[track_caller]
+    pub fn try_read_for(&self, timeout: Duration) -> TryLockResult<ReadGuard<'_, T, R>> {
+        match self.inner.try_read_for(timeout) {
+            Some(inner) => Ok(self.read_guard(inner)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Like `read()`, but gives up and returns `WouldBlock` once `deadline` has passed.
+    #[track_caller]
+    pub fn try_read_until(&self, deadline: Instant) -> TryLockResult<ReadGuard<'_, T, R>> {
+        match self.inner.try_read_until(deadline) {
+            Some(inner) => Ok(self.read_guard(inner)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Like `write()`, but gives up and returns `WouldBlock` after `timeout`.
+    #[track_caller]
+    pub fn try_write_for(&self, timeout: Duration) -> TryLockResult<WriteGuard<'_, T, R>> {
+        match self.inner.try_write_for(timeout) {
+            Some(inner) => Ok(self.write_guard(inner)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Like `write()`, but gives up and returns `WouldBlock` once `deadline` has passed.
+    #[track_caller]
+    pub fn try_write_until(&self, deadline: Instant) -> TryLockResult<WriteGuard<'_, T, R>> {
+        match self.inner.try_write_until(deadline) {
+            Some(inner) => Ok(self.write_guard(inner)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+}
+
+impl<T, R: lock_api::RawRwLockUpgrade> RwLock<T, R> {
+    /// A read lock that can later be upgraded to a write lock,
+    /// see `UpgradableReadGuard::upgrade()`.
+    ///
+    /// Other readers can still come in, but only one thread
+    /// can have an upgradable read lock at a time.
+    #[track_caller]
+    pub fn upgradable_read(&self) -> LockResult<UpgradableReadGuard<'_, T, R>> {
+        let guard = UpgradableReadGuard {
+            inner: self.inner.upgradable_read(),
+            poison_flag: &self.poison,
+        };
+        if self.poison.get() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, Ordering::Relaxed},
+            Barrier,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{Preference, ReadGuard, RwLock, UpgradableReadGuard, WriteGuard};
+    use crate::lock_api::RawRwLock as _;
+    use crate::{mutex::Mutex, poison::TryLockError};
+
+    #[test]
+    fn poison() {
+        let lock = RwLock::new(vec![1]);
+        thread::scope(|s| {
+            // Panicking readers don't poison.
+            let r = s.spawn(|| {
+                let _g = lock.read().unwrap();
+                panic!("oops");
+            });
+            assert!(r.join().is_err());
+            assert!(!lock.is_poisoned());
+
+            let r = s.spawn(|| {
+                let mut g = lock.write().unwrap();
+                g.push(2);
+                panic!("oops");
+            });
+            assert!(r.join().is_err());
+        });
+        assert!(lock.is_poisoned());
+        assert_eq!(*lock.read().err().unwrap().into_inner(), [1, 2]);
+        lock.clear_poison();
+        assert_eq!(*lock.write().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn map() {
+        let lock = RwLock::new((1, vec![2, 3]));
+        {
+            let a = ReadGuard::map(lock.read().unwrap(), |(_, v)| &v[..]);
+            let b = ReadGuard::try_map(lock.read().unwrap(), |(_, v)| v.get(1))
+                .ok()
+                .unwrap();
+            assert_eq!(a.len(), 2);
+            assert_eq!(*b, 3);
+        }
+        let mut w = WriteGuard::map(lock.write().unwrap(), |(n, _)| n);
+        *w += 1;
+        drop(w);
+        assert!(WriteGuard::try_map(lock.write().unwrap(), |(_, v)| v.get_mut(5)).is_err());
+        assert_eq!(lock.read().unwrap().0, 2);
+    }
+
+    #[test]
+    fn upgradable() {
+        let cache = RwLock::new(HashMap::new());
+        thread::scope(|s| {
+            for i in 0..4 {
+                let cache = &cache;
+                s.spawn(move || {
+                    for key in 0..100 {
+                        let g = cache.upgradable_read().unwrap();
+                        if g.contains_key(&key) {
+                            continue;
+                        }
+                        let mut g = UpgradableReadGuard::upgrade(g);
+                        assert!(g.insert(key, i).is_none());
+                        // Readers can come in again, but the entry stays.
+                        let g = WriteGuard::downgrade(g);
+                        assert!(g.contains_key(&key));
+                    }
+                });
+            }
+        });
+        assert_eq!(cache.read().unwrap().len(), 100);
+
+        let lock = RwLock::new(0);
+        let u = lock.upgradable_read().unwrap();
+        assert!(lock.inner.try_upgradable_read().is_none());
+        let reading = Barrier::new(2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let r = lock.read().unwrap();
+                reading.wait();
+                thread::sleep(Duration::from_millis(50));
+                assert_eq!(*r, 0);
+            });
+            reading.wait();
+            let u = UpgradableReadGuard::try_upgrade(u).err().unwrap();
+            // Waits for the reader above.
+            *UpgradableReadGuard::upgrade(u) += 1;
+        });
+        assert_eq!(*lock.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn timeouts() {
+        let lock = RwLock::new(0);
+        let r = lock.read().unwrap();
+        assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+        // Gives up after setting the odd bit, which must not keep readers out.
+        assert!(lock.try_write_for(Duration::from_millis(50)).is_err());
+        assert!(lock.try_read().is_ok());
+        thread::scope(|s| {
+            // A writer that does get the lock, after one that gave up.
+            s.spawn(|| *lock.try_write_for(Duration::from_secs(10)).unwrap() += 1);
+            assert!(lock.try_write_for(Duration::from_millis(20)).is_err());
+            thread::sleep(Duration::from_millis(20));
+            drop(r);
+        });
+
+        let w = lock.write().unwrap();
+        assert!(matches!(lock.try_read(), Err(TryLockError::WouldBlock)));
+        let start = Instant::now();
+        assert!(lock
+            .try_read_until(start + Duration::from_millis(50))
+            .is_err());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        drop(w);
+        assert_eq!(*lock.try_read_for(Duration::ZERO).unwrap(), 1);
+    }
+
+    #[test]
+    fn preference() {
+        // With a reader in, a waiting writer keeps new readers out, except with `Readers`.
+        for (preference, reader_gets_in) in [
+            (Preference::Readers, true),
+            (Preference::Writers, false),
+            (Preference::PhaseFair, false),
+        ] {
+            let lock = RwLock::new(0).with_preference(preference);
+            let r = lock.read().unwrap();
+            thread::scope(|s| {
+                s.spawn(|| *lock.write().unwrap() += 1);
+                thread::sleep(Duration::from_millis(50));
+                assert_eq!(lock.try_read().is_ok(), reader_gets_in, "{preference:?}");
+                drop(r);
+            });
+            assert_eq!(*lock.read().unwrap(), 1);
+        }
+
+        // With `PhaseFair`, a reader that waited for a writer gets in before the next writer,
+        // even if that one was waiting first.
+        let lock = RwLock::new(()).with_preference(Preference::PhaseFair);
+        let order = Mutex::new(Vec::new());
+        let w = lock.write().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _w = lock.write().unwrap();
+                order.lock().unwrap().push("writer");
+            });
+            thread::sleep(Duration::from_millis(50));
+            s.spawn(|| {
+                let _r = lock.read().unwrap();
+                order.lock().unwrap().push("reader");
+            });
+            thread::sleep(Duration::from_millis(50));
+            drop(w);
+        });
+        assert_eq!(*order.lock().unwrap(), ["reader", "writer"]);
+    }
+
+    /// Takes 10 write locks while 3 threads keep taking read locks back to back,
+    /// or the other way around. Hangs if the lock lets those threads starve us.
+    fn get_through(preference: Preference, busy_readers: bool) {
+        let lock = RwLock::new(0).with_preference(preference);
+        let stop = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    while !stop.load(Relaxed) {
+                        if busy_readers {
+                            let _r = lock.read().unwrap();
+                            thread::yield_now();
+                        } else {
+                            *lock.write().unwrap() += 1;
+                        }
+                    }
+                });
+            }
+            for _ in 0..10 {
+                if busy_readers {
+                    *lock.write().unwrap() += 1;
+                } else {
+                    drop(lock.read().unwrap());
+                }
+            }
+            stop.store(true, Relaxed);
+        });
+    }
+
+    #[test]
+    fn no_starvation() {
+        get_through(Preference::Readers, false);
+        get_through(Preference::Writers, true);
+        get_through(Preference::PhaseFair, false);
+        get_through(Preference::PhaseFair, true);
+    }
+
+    #[test]
+    fn phase_fair_reader_timeout() {
+        let lock = RwLock::new(0).with_preference(Preference::PhaseFair);
+        let raw = &lock.inner.raw;
+        raw.lock_exclusive();
+        thread::scope(|s| {
+            let reader = s.spawn(|| lock.try_read_for(Duration::from_millis(100)).is_ok());
+            while raw.waiting_readers[0].load(Relaxed) == 0
+                || raw.sleeping_readers.load(Relaxed) == 0
+            {
+                thread::yield_now();
+            }
+            // Unlock the way `wake_after_exclusive()` does with another writer waiting,
+            // but without waking the reader, as if its timeout had already fired. The next
+            // writer then has to wait for it, as it's from the previous phase.
+            raw.write_phase.store(1, Relaxed);
+            raw.state.store(1, Relaxed);
+            let writer = s.spawn(|| lock.try_write_for(Duration::from_secs(5)).is_ok());
+            assert!(!reader.join().unwrap());
+            // The reader that gave up wakes the writer, instead of leaving it asleep.
+            assert!(writer.join().unwrap());
+        });
     }
 }
@@ -0,0 +1,176 @@
+use std::{
+    cell::{Cell, UnsafeCell},
+    marker::PhantomData,
+    ops::Deref,
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+};
+
+use crate::{lock_api::RawMutex as _, mutex::RawMutex};
+
+/// A mutex that can be locked again by the thread that already holds it.
+///
+/// Since the same thread can hold several guards at once,
+/// they only give out `&T`. Use a `Cell` or `RefCell` inside for mutation.
+pub struct ReentrantMutex<T> {
+    raw: RawMutex,
+    /// `current_thread_id()` of the owner, 0 if unlocked.
+    owner: AtomicUsize,
+    /// Number of guards the owner holds. Only touched by the owner.
+    lock_count: UnsafeCell<u32>,
+    value: T,
+}
+
+unsafe impl<T> Sync for ReentrantMutex<T> where T: Send {}
+
+pub struct ReentrantMutexGuard<'a, T> {
+    mutex: &'a ReentrantMutex<T>,
+    /// Must be dropped on the thread that locked it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for ReentrantMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.mutex.value
+    }
+}
+
+impl<T> Drop for ReentrantMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Safety: we're the owner, so nobody else touches the count.
+        unsafe {
+            let count = &mut *self.mutex.lock_count.get();
+            *count -= 1;
+            if *count == 0 {
+                self.mutex.owner.store(0, Relaxed);
+                self.mutex.raw.unlock();
+            }
+        }
+    }
+}
+
+impl<T> ReentrantMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            raw: RawMutex::new(),
+            owner: AtomicUsize::new(0),
+            lock_count: UnsafeCell::new(0),
+            value,
+        }
+    }
+
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, T> {
+        let this_thread = current_thread_id();
+        // Relaxed is enough: only this thread can have stored its own id,
+        // and any other value just means we don't own it.
+        if self.owner.load(Relaxed) != this_thread {
+            self.raw.lock();
+            self.owner.store(this_thread, Relaxed);
+        }
+        self.guard()
+    }
+
+    pub fn try_lock(&self) -> Option<ReentrantMutexGuard<'_, T>> {
+        let this_thread = current_thread_id();
+        if self.owner.load(Relaxed) != this_thread {
+            if !self.raw.try_lock() {
+                return None;
+            }
+            self.owner.store(this_thread, Relaxed);
+        }
+        Some(self.guard())
+    }
+
+    /// Must only be called by the owner.
+    fn guard(&self) -> ReentrantMutexGuard<'_, T> {
+        // Safety: we're the owner.
+        unsafe {
+            let count = &mut *self.lock_count.get();
+            *count = count.checked_add(1).expect("lock count overflow");
+        }
+        ReentrantMutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+/// A number that is unique for each thread, and never 0.
+///
+/// Never reused, not even after a thread exits, so a thread can't inherit
+/// the ownership of a leaked guard from an earlier thread.
+pub(crate) fn current_thread_id() -> usize {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+    thread_local!(static ID: Cell<usize> = const { Cell::new(0) });
+    ID.with(|id| {
+        if id.get() == 0 {
+            let next = NEXT_ID.fetch_add(1, Relaxed);
+            assert_ne!(next, 0, "ran out of thread ids");
+            id.set(next);
+        }
+        id.get()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, thread};
+
+    use super::ReentrantMutex;
+
+    #[test]
+    fn reentrant() {
+        let m = ReentrantMutex::new(RefCell::new(Vec::new()));
+        thread::scope(|s| {
+            for i in 0..2 {
+                let m = &m;
+                s.spawn(move || {
+                    let a = m.lock();
+                    // Would deadlock with a plain Mutex.
+                    let b = m.lock();
+                    a.borrow_mut().push(i);
+                    b.borrow_mut().push(i);
+                });
+            }
+        });
+        let v = m.into_inner().into_inner();
+        assert!(v == [0, 0, 1, 1] || v == [1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = ReentrantMutex::new(0);
+        let g = m.lock();
+        assert!(m.try_lock().is_some());
+        thread::scope(|s| {
+            s.spawn(|| assert!(m.try_lock().is_none()));
+        });
+        drop(g);
+        thread::scope(|s| {
+            s.spawn(|| assert!(m.try_lock().is_some()));
+        });
+    }
+
+    #[test]
+    fn leaked_guard() {
+        let m = ReentrantMutex::new(0);
+        thread::scope(|s| {
+            s.spawn(|| std::mem::forget(m.lock()));
+        });
+        // Later threads may get the exited thread's thread locals,
+        // but not its ownership.
+        for _ in 0..10 {
+            thread::scope(|s| {
+                s.spawn(|| assert!(m.try_lock().is_none()));
+            });
+        }
+    }
+}
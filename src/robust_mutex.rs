@@ -0,0 +1,257 @@
+//! A mutex that can live in memory shared between processes,
+//! and that notices when its owner died while holding it.
+//!
+//! `Mutex` can't do either: `atomic_wait` uses private futexes, which only
+//! work within one process, and if the owner dies the state stays locked forever.
+//!
+//! This is a process-shared, robust `pthread_mutex_t`. glibc keeps the thread id
+//! of the owner in the lock word and links every locked robust mutex into the
+//! thread's robust list (`set_robust_list(2)`). When a thread or process exits,
+//! the kernel walks that list, marks each mutex with `FUTEX_OWNER_DIED` and wakes
+//! a waiter. We can't register a robust list of our own, since there is only one
+//! per thread and glibc already owns it, so we let glibc do all of this.
+
+use std::{
+    cell::UnsafeCell,
+    error::Error,
+    fmt, io,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    ptr::addr_of_mut,
+};
+
+/// A robust, process-shared mutex, see the module documentation.
+///
+/// It can't be moved once initialized, so it's only made in place with `init()`,
+/// typically inside an `mmap`'d region. `T` ends up in that memory too, so it
+/// shouldn't contain pointers or anything else that only means something
+/// in one process.
+#[repr(C)]
+pub struct RobustMutex<T> {
+    raw: UnsafeCell<libc::pthread_mutex_t>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for RobustMutex<T> where T: Send {}
+
+/// Why `lock()` or `try_lock()` didn't simply succeed.
+pub enum LockError<G> {
+    /// The previous owner died while holding the lock. We hold it now, but the
+    /// data may be half updated. Repair it and call `make_consistent()`, or the
+    /// mutex becomes unusable once the guard is dropped.
+    OwnerDied(G),
+    /// An earlier owner died and the next one didn't call `make_consistent()`.
+    /// The mutex can't be locked anymore.
+    NotRecoverable,
+    /// Only from `try_lock()`: somebody else holds the lock.
+    WouldBlock,
+}
+
+impl<G> fmt::Debug for LockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OwnerDied(_) => f.write_str("OwnerDied(..)"),
+            Self::NotRecoverable => f.write_str("NotRecoverable"),
+            Self::WouldBlock => f.write_str("WouldBlock"),
+        }
+    }
+}
+
+impl<G> fmt::Display for LockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OwnerDied(_) => f.write_str("the owner of the mutex died while holding it"),
+            Self::NotRecoverable => f.write_str("the mutex is not recoverable"),
+            Self::WouldBlock => f.write_str("try_lock failed because the operation would block"),
+        }
+    }
+}
+
+impl<G> Error for LockError<G> {}
+
+pub struct RobustMutexGuard<'a, T> {
+    mutex: &'a RobustMutex<T>,
+    /// Robust mutexes must be unlocked by the thread that locked them.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for RobustMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for RobustMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for RobustMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { libc::pthread_mutex_unlock(self.mutex.raw.get()) };
+    }
+}
+
+impl<T> RobustMutexGuard<'_, T> {
+    /// Marks the data as repaired after `LockError::OwnerDied`,
+    /// so the mutex keeps working after this guard is dropped.
+    ///
+    /// This is an associated function so it can't shadow a method of `T`,
+    /// call it as `RobustMutexGuard::make_consistent(&guard)`.
+    pub fn make_consistent(guard: &Self) {
+        // Only fails if the mutex isn't in the owner died state, which is fine.
+        unsafe { libc::pthread_mutex_consistent(guard.mutex.raw.get()) };
+    }
+}
+
+impl<T> RobustMutex<T> {
+    /// Initializes a mutex holding `value` at `place`.
+    ///
+    /// # Safety
+    ///
+    /// `place` must be valid for writes and suitably aligned, must not be
+    /// moved afterwards, and must stay valid for `'a`. Only one process should
+    /// call this, the others use `from_ptr()` once it's done.
+    pub unsafe fn init<'a>(place: *mut Self, value: T) -> io::Result<&'a Self> {
+        let mut attr = MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
+        check(libc::pthread_mutexattr_init(attr.as_mut_ptr()))?;
+        let r = (|| {
+            check(libc::pthread_mutexattr_setpshared(
+                attr.as_mut_ptr(),
+                libc::PTHREAD_PROCESS_SHARED,
+            ))?;
+            check(libc::pthread_mutexattr_setrobust(
+                attr.as_mut_ptr(),
+                libc::PTHREAD_MUTEX_ROBUST,
+            ))?;
+            check(libc::pthread_mutex_init(
+                UnsafeCell::raw_get(addr_of_mut!((*place).raw)),
+                attr.as_ptr(),
+            ))?;
+            // Only now, so `value` is dropped if anything above failed.
+            addr_of_mut!((*place).value).write(UnsafeCell::new(value));
+            Ok(())
+        })();
+        libc::pthread_mutexattr_destroy(attr.as_mut_ptr());
+        r.map(|()| &*place)
+    }
+
+    /// Uses a mutex that was initialized by `init()`, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to an initialized `RobustMutex<T>` that stays valid for `'a`.
+    pub unsafe fn from_ptr<'a>(ptr: *const Self) -> &'a Self {
+        &*ptr
+    }
+
+    pub fn lock(&self) -> Result<RobustMutexGuard<'_, T>, LockError<RobustMutexGuard<'_, T>>> {
+        self.result(unsafe { libc::pthread_mutex_lock(self.raw.get()) })
+    }
+
+    pub fn try_lock(&self) -> Result<RobustMutexGuard<'_, T>, LockError<RobustMutexGuard<'_, T>>> {
+        self.result(unsafe { libc::pthread_mutex_trylock(self.raw.get()) })
+    }
+
+    fn result(
+        &self,
+        r: libc::c_int,
+    ) -> Result<RobustMutexGuard<'_, T>, LockError<RobustMutexGuard<'_, T>>> {
+        let guard = || RobustMutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        };
+        match r {
+            0 => Ok(guard()),
+            libc::EOWNERDEAD => Err(LockError::OwnerDied(guard())),
+            libc::ENOTRECOVERABLE => Err(LockError::NotRecoverable),
+            libc::EBUSY => Err(LockError::WouldBlock),
+            e => panic!(
+                "pthread_mutex_lock failed: {}",
+                io::Error::from_raw_os_error(e)
+            ),
+        }
+    }
+
+    /// Destroys the mutex and gives back the value.
+    ///
+    /// # Safety
+    ///
+    /// No process may use the mutex anymore, and `ptr` must have come from `init()`.
+    pub unsafe fn destroy(ptr: *mut Self) -> T {
+        libc::pthread_mutex_destroy((*ptr).raw.get());
+        addr_of_mut!((*ptr).value).read().into_inner()
+    }
+}
+
+fn check(r: libc::c_int) -> io::Result<()> {
+    match r {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{mem, ptr, thread};
+
+    use super::{LockError, RobustMutex, RobustMutexGuard};
+
+    #[test]
+    fn owner_died() {
+        unsafe {
+            let mem = libc::mmap(
+                ptr::null_mut(),
+                mem::size_of::<RobustMutex<u32>>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(mem, libc::MAP_FAILED);
+            let m = RobustMutex::init(mem.cast(), 0u32).unwrap();
+
+            let pid = libc::fork();
+            if pid == 0 {
+                // Die with the lock held, halfway through an update.
+                let mut g = m.lock().unwrap_or_else(|_| libc::_exit(1));
+                *g = 1;
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+
+            let Err(LockError::OwnerDied(mut g)) = m.lock() else {
+                panic!("expected OwnerDied");
+            };
+            assert_eq!(*g, 1);
+            *g = 2;
+            RobustMutexGuard::make_consistent(&g);
+            drop(g);
+            assert_eq!(*m.lock().unwrap(), 2);
+
+            assert_eq!(RobustMutex::destroy(mem.cast::<RobustMutex<u32>>()), 2);
+            libc::munmap(mem, mem::size_of::<RobustMutex<u32>>());
+        }
+    }
+
+    #[test]
+    fn not_recoverable() {
+        let mut place = mem::MaybeUninit::<RobustMutex<u32>>::uninit();
+        let m = unsafe { RobustMutex::init(place.as_mut_ptr(), 0) }.unwrap();
+        thread::scope(|s| {
+            // A thread exiting with the lock held counts as dying too.
+            s.spawn(|| mem::forget(m.lock().unwrap()));
+        });
+        assert!(matches!(m.lock(), Err(LockError::OwnerDied(_))));
+        // Dropped without make_consistent().
+        assert!(matches!(m.lock(), Err(LockError::NotRecoverable)));
+        assert!(matches!(m.try_lock(), Err(LockError::NotRecoverable)));
+    }
+}
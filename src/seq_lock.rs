@@ -0,0 +1,184 @@
+//! A sequence lock: readers never write to shared memory, so they don't
+//! bounce a cache line between cores the way `RwLock::read()` does.
+//!
+//! Writers take a lock to exclude each other, and make `seq` odd while they
+//! write. Readers copy the data without locking anything, and try again if
+//! `seq` was odd or changed in the meantime. This only works for `Copy` data,
+//! since a reader may copy a half-written value, which it then throws away.
+
+use std::{
+    cell::UnsafeCell,
+    hint,
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{
+        fence, AtomicUsize,
+        Ordering::{Acquire, Relaxed, Release},
+    },
+    thread,
+};
+
+use crate::{lock_api, spin::RawSpinLock};
+
+/// A sequence lock around a `Copy` value, see the module documentation.
+///
+/// Writers exclude each other with `R`, a `SpinLock` by default,
+/// for example `SeqLock::<_, mutex::RawMutex>::new(x)` to sleep instead.
+pub struct SeqLock<T, R: lock_api::RawMutex = RawSpinLock> {
+    /// Odd while a writer is writing.
+    seq: AtomicUsize,
+    writer: lock_api::Mutex<R, ()>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send, R: lock_api::RawMutex + Sync> Sync for SeqLock<T, R> {}
+
+impl<T: Copy, R: lock_api::RawMutex> SeqLock<T, R> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            writer: lock_api::Mutex::new(()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Copies the value out, retrying for as long as writers get in the way.
+    pub fn read(&self) -> T {
+        let mut attempt = 0u32;
+        loop {
+            if let Some(value) = self.try_read() {
+                return value;
+            }
+            // A writer that got preempted halfway keeps us out,
+            // so give it a chance to run.
+            if attempt < 100 {
+                hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+            attempt = attempt.saturating_add(1);
+        }
+    }
+
+    /// Copies the value out, or returns `None` if a writer got in the way.
+    pub fn try_read(&self) -> Option<T> {
+        // Acquire matches the Release store at the end of `write()`,
+        // so we see everything written before it.
+        let seq = self.seq.load(Acquire);
+        if seq % 2 == 1 {
+            return None;
+        }
+
+        // This races with a writer, but the copy is only used once `seq`
+        // tells us there was none. Volatile, so the compiler can't assume
+        // the memory didn't change, and `MaybeUninit`, so a torn copy of a
+        // type like `bool` is never treated as a valid value.
+        let value = unsafe { ptr::read_volatile(self.value.get().cast::<MaybeUninit<T>>()) };
+
+        // Acquire fence matches the Release fence in `write()`: if we read
+        // anything the writer wrote after it made `seq` odd, then the load
+        // below sees that odd value (or a later one).
+        fence(Acquire);
+        if self.seq.load(Relaxed) != seq {
+            return None;
+        }
+        // Safety: No writer touched the value while we copied it.
+        Some(unsafe { value.assume_init() })
+    }
+
+    pub fn write(&self, value: T) {
+        let _writer = self.writer.lock();
+        self.publish(value);
+    }
+
+    /// Changes the value with `f`, without another writer getting in between.
+    ///
+    /// `f` works on a copy, so if it panics, nothing has changed.
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        let _writer = self.writer.lock();
+        // Safety: Only writers write, and we're the only writer.
+        let mut value = unsafe { *self.value.get() };
+        f(&mut value);
+        self.publish(value);
+    }
+
+    /// Must only be called while holding `writer`.
+    fn publish(&self, value: T) {
+        // Only writers change `seq`, and we're the only writer.
+        let seq = self.seq.load(Relaxed);
+        self.seq.store(seq.wrapping_add(1), Relaxed);
+        // Release fence, so a reader that sees any of the writes below
+        // also sees the odd `seq` after its Acquire fence.
+        fence(Release);
+        unsafe { ptr::write_volatile(self.value.get(), value) };
+        // Release, so a reader that sees the new even `seq` sees the new value.
+        self.seq.store(seq.wrapping_add(2), Release);
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering::Relaxed},
+        thread,
+    };
+
+    use super::SeqLock;
+    use crate::mutex::RawMutex;
+
+    #[test]
+    fn seq_lock() {
+        // Big enough that copying it isn't a single instruction.
+        let lock = SeqLock::<_>::new([0u64; 8]);
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    let mut last = 0;
+                    while !done.load(Relaxed) {
+                        let v = lock.read();
+                        assert!(v.iter().all(|&x| x == v[0]), "torn read: {v:?}");
+                        assert!(v[0] >= last);
+                        last = v[0];
+                    }
+                });
+            }
+            for i in 1..=10_000 {
+                if i % 2 == 0 {
+                    lock.write([i; 8]);
+                } else {
+                    lock.update(|v| *v = [v[0] + 1; 8]);
+                }
+            }
+            done.store(true, Relaxed);
+        });
+        assert_eq!(lock.into_inner(), [10_000; 8]);
+    }
+
+    #[test]
+    fn mutex_writers() {
+        let lock = SeqLock::<_, RawMutex>::new((0, 0));
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        lock.update(|(a, b)| {
+                            *a += 1;
+                            *b += 2;
+                        });
+                    }
+                });
+            }
+        });
+        assert_eq!(lock.read(), (4000, 8000));
+        assert!(lock.try_read().is_some());
+    }
+}
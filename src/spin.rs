@@ -1,67 +1,250 @@
-use std::cell::UnsafeCell;
-use std::ops::{Deref, DerefMut};
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Acquire, Release};
+//! Locks that never sleep: they spin (and yield) until they get the lock.
+//!
+//! `SpinLock` is a test-and-test-and-set lock: waiters only read the lock
+//! word until it looks free, so they share its cache line instead of taking it
+//! away from each other (and from the holder) with every attempt. It's fast,
+//! but not fair: whoever happens to try right after an unlock gets it.
+//! `TicketLock` serves waiters in the order they arrived.
 
-pub struct SpinLock<T> {
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicBool, AtomicU32};
+
+use crate::backoff::{Spin, SpinPolicy};
+use crate::lock_api;
+use crate::lockdep;
+use crate::stats::{LockStats, Timer};
+
+/// The lock part of `SpinLock`, without the data.
+pub struct RawSpinLock {
     locked: AtomicBool,
-    value: UnsafeCell<T>,
+    spin: &'static dyn SpinPolicy,
+    stats: LockStats,
+    lockdep: lockdep::Class,
 }
 
-pub struct SpinGuard<'a, T> {
-    lock: &'a SpinLock<T>,
+impl RawSpinLock {
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            spin: &Spin { limit: u32::MAX },
+            stats: LockStats::new(),
+            lockdep: lockdep::Class::new(),
+        }
+    }
 }
 
-impl<T> Deref for SpinGuard<'_, T> {
-    type Target = T;
-    fn deref(&self) -> &Self::Target {
-        // Safety: The very existence of this Guard
-        // guarantees we've exclusively locked the lock.
-        unsafe { &*self.lock.value.get() }
+impl Default for RawSpinLock {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl<T> DerefMut for SpinGuard<'_, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        // Safety: The very existence of this Guard
-        // guarantees we've exclusively locked the lock.
-        unsafe { &mut *self.lock.value.get() }
+unsafe impl lock_api::RawMutex for RawSpinLock {
+    const INIT: Self = Self::new();
+
+    const NAME: &'static str = "SpinLock";
+
+    // Lockdep tracks the held locks per thread.
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock(&self) {
+        self.lockdep.check();
+        if !self.locked.swap(true, Acquire) {
+            self.stats.uncontended();
+            self.stats.acquired();
+            self.lockdep.acquired();
+            return;
+        }
+
+        let timer = Timer::start();
+        let mut attempt = 0u32;
+        loop {
+            // Only try to take it once it looks free, since the swap
+            // takes the cache line away from everyone else.
+            while self.locked.load(Relaxed) {
+                // We can't sleep, so when the policy gives up on spinning,
+                // at least let another thread (hopefully the holder) run.
+                if !self.spin.backoff(attempt) {
+                    std::thread::yield_now();
+                }
+                attempt = attempt.saturating_add(1);
+            }
+            if !self.locked.swap(true, Acquire) {
+                break;
+            }
+        }
+        self.stats.contended();
+        self.stats.spins(attempt);
+        self.stats.waited(timer);
+        self.stats.acquired();
+        self.lockdep.acquired();
+    }
+
+    fn try_lock(&self) -> bool {
+        let locked = self.try_lock_quiet();
+        if locked {
+            self.stats.uncontended();
+            self.stats.acquired();
+            self.lockdep.acquired();
+        }
+        locked
     }
-}
 
-impl<T> Drop for SpinGuard<'_, T> {
-    fn drop(&mut self) {
-        self.lock.locked.store(false, Release)
+    unsafe fn unlock(&self) {
+        self.lockdep.released();
+        self.stats.released();
+        self.unlock_quiet();
+    }
+
+    fn try_lock_quiet(&self) -> bool {
+        !self.locked.load(Relaxed) && !self.locked.swap(true, Acquire)
+    }
+
+    unsafe fn unlock_quiet(&self) {
+        self.locked.store(false, Release)
     }
 }
 
-/// Make SpinLock Sync if T is Send
-unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+/// A lock that never sleeps, it spins (and yields) until it gets the lock.
+pub type SpinLock<T> = lock_api::Mutex<RawSpinLock, T>;
+pub type SpinGuard<'a, T> = lock_api::MutexGuard<'a, RawSpinLock, T>;
+/// A `SpinGuard` made by `SpinGuard::map()`, pointing to a part of the data.
+pub type MappedSpinGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawSpinLock, T>;
 
 impl<T> SpinLock<T> {
-    pub const fn new(value: T) -> Self {
+    /// Replaces the default policy of spinning without ever backing off.
+    pub const fn with_spin_policy(mut self, spin: &'static dyn SpinPolicy) -> Self {
+        self.raw.spin = spin;
+        self
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::StatsSnapshot {
+        self.raw.stats.snapshot()
+    }
+}
+
+/// The lock part of `TicketLock`, without the data.
+pub struct RawTicketLock {
+    /// The ticket the next thread to arrive takes.
+    next_ticket: AtomicU32,
+    /// The ticket of the thread that holds the lock, or gets it next.
+    now_serving: AtomicU32,
+    spin: &'static dyn SpinPolicy,
+    stats: LockStats,
+    lockdep: lockdep::Class,
+}
+
+impl RawTicketLock {
+    pub const fn new() -> Self {
         Self {
-            locked: AtomicBool::new(false),
-            value: UnsafeCell::new(value),
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            // Only the thread next in line can get the lock, and it might not
+            // be running, so yield soon rather than spin out our time slice.
+            spin: &Spin { limit: 100 },
+            stats: LockStats::new(),
+            lockdep: lockdep::Class::new(),
+        }
+    }
+}
+
+impl Default for RawTicketLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawTicketLock {
+    const INIT: Self = Self::new();
+
+    const NAME: &'static str = "TicketLock";
+
+    // Lockdep tracks the held locks per thread.
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock(&self) {
+        self.lockdep.check();
+        let ticket = self.next_ticket.fetch_add(1, Relaxed);
+        // Acquire matches the Release in `unlock()`.
+        if self.now_serving.load(Acquire) == ticket {
+            self.stats.uncontended();
+            self.stats.acquired();
+            self.lockdep.acquired();
+            return;
+        }
+
+        let timer = Timer::start();
+        let mut attempt = 0u32;
+        while self.now_serving.load(Acquire) != ticket {
+            if !self.spin.backoff(attempt) {
+                std::thread::yield_now();
+            }
+            attempt = attempt.saturating_add(1);
         }
+        self.stats.contended();
+        self.stats.spins(attempt);
+        self.stats.waited(timer);
+        self.stats.acquired();
+        self.lockdep.acquired();
     }
 
-    pub fn lock(&self) -> SpinGuard<T> {
-        while self.locked.swap(true, Acquire) {
-            // tells the processor that we're spinning,
-            // this hint will result in a special instruction that
-            // causes the processor core to optimizeits behavior
-            std::hint::spin_loop();
+    fn try_lock(&self) -> bool {
+        let locked = self.try_lock_quiet();
+        if locked {
+            self.stats.uncontended();
+            self.stats.acquired();
+            self.lockdep.acquired();
         }
-        SpinGuard { lock: self }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        self.lockdep.released();
+        self.stats.released();
+        self.unlock_quiet();
+    }
+
+    fn try_lock_quiet(&self) -> bool {
+        // Only take a ticket if it would be served right away. Acquire, because
+        // if it is, we get the lock from whoever made `now_serving` this value.
+        let serving = self.now_serving.load(Acquire);
+        self.next_ticket
+            .compare_exchange(serving, serving.wrapping_add(1), Relaxed, Relaxed)
+            .is_ok()
+    }
+
+    unsafe fn unlock_quiet(&self) {
+        // Only the holder changes `now_serving`.
+        let serving = self.now_serving.load(Relaxed);
+        self.now_serving.store(serving.wrapping_add(1), Release);
+    }
+}
+
+/// A spin lock that hands out the lock in the order threads asked for it.
+pub type TicketLock<T> = lock_api::Mutex<RawTicketLock, T>;
+pub type TicketGuard<'a, T> = lock_api::MutexGuard<'a, RawTicketLock, T>;
+/// A `TicketGuard` made by `TicketGuard::map()`, pointing to a part of the data.
+pub type MappedTicketGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawTicketLock, T>;
+
+impl<T> TicketLock<T> {
+    /// Replaces the default policy of spinning 100 times before yielding.
+    pub const fn with_spin_policy(mut self, spin: &'static dyn SpinPolicy) -> Self {
+        self.raw.spin = spin;
+        self
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::StatsSnapshot {
+        self.raw.stats.snapshot()
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::thread;
+    use std::{sync::atomic::Ordering::Relaxed, thread};
 
-    use super::SpinLock;
+    use super::{SpinGuard, SpinLock, TicketLock};
 
     #[test]
     fn spin_lock() {
@@ -77,4 +260,55 @@ mod test {
         let g = x.lock();
         assert!(g.as_slice() == [1, 2, 2] || g.as_slice() == [2, 2, 1]);
     }
+
+    #[test]
+    fn map() {
+        let x = SpinLock::new((1, String::from("a")));
+        let mut s = SpinGuard::map(x.lock(), |(_, s)| s);
+        s.push('b');
+        drop(s);
+
+        // The lock is still held by the returned guard.
+        let g = SpinGuard::try_map(x.lock(), |_| None::<&mut i32>)
+            .err()
+            .unwrap();
+        assert_eq!(g.1, "ab");
+    }
+
+    #[test]
+    fn accessors() {
+        let mut x = SpinLock::<Vec<i32>>::default();
+        let g = x.try_lock().unwrap();
+        assert!(x.try_lock().is_none());
+        assert_eq!(format!("{x:?}"), "SpinLock { data: <locked>, .. }");
+        drop(g);
+        x.get_mut().push(1);
+        assert_eq!(format!("{x:?}"), "SpinLock { data: [1], .. }");
+        // Looking at it doesn't count as locking it.
+        #[cfg(feature = "stats")]
+        assert_eq!(x.stats().uncontended, 1);
+        assert_eq!(x.into_inner(), [1]);
+        let x = TicketLock::new(2);
+        assert_eq!(format!("{x:?}"), "TicketLock { data: 2, .. }");
+        assert_eq!(*x.try_lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn ticket_lock() {
+        let x = TicketLock::new(Vec::new());
+        thread::scope(|s| {
+            let g = x.lock();
+            assert!(x.try_lock().is_none());
+            for i in 1..=3 {
+                let x = &x;
+                s.spawn(move || x.lock().push(i));
+                // Wait until this thread has its ticket, so they line up in order.
+                while x.raw.next_ticket.load(Relaxed) != i + 1 {
+                    thread::yield_now();
+                }
+            }
+            drop(g);
+        });
+        assert_eq!(x.into_inner(), [1, 2, 3]);
+    }
 }
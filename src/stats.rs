@@ -0,0 +1,346 @@
+//! Contention statistics, only recorded with the `stats` cargo feature.
+//!
+//! Without the feature, `LockStats` is empty and all of its methods do nothing,
+//! so the locks can call them unconditionally.
+
+#[cfg(feature = "stats")]
+pub use enabled::{Histogram, StatsSnapshot};
+#[cfg(feature = "stats")]
+pub(crate) use enabled::{LockStats, Timer};
+
+#[cfg(not(feature = "stats"))]
+pub(crate) use disabled::{LockStats, Timer};
+
+#[cfg(feature = "stats")]
+mod enabled {
+    use std::{
+        fmt::Write,
+        sync::{
+            atomic::{AtomicU64, Ordering::Relaxed},
+            OnceLock,
+        },
+        time::{Duration, Instant},
+    };
+
+    /// Upper bounds of the histogram buckets: 256ns, 1µs, 4µs, ... up to ~4.3s.
+    const BUCKETS: [u64; 13] = {
+        let mut b = [0; 13];
+        let mut i = 0;
+        while i < b.len() {
+            b[i] = 1 << (8 + 2 * i);
+            i += 1;
+        }
+        b
+    };
+
+    /// Nanoseconds since the first time anything was timed.
+    fn now() -> u64 {
+        static EPOCH: OnceLock<Instant> = OnceLock::new();
+        EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+    }
+
+    #[derive(Clone, Copy)]
+    pub(crate) struct Timer(u64);
+
+    impl Timer {
+        pub(crate) fn start() -> Self {
+            Self(now())
+        }
+    }
+
+    struct AtomicHistogram {
+        /// Not cumulative, the last one counts everything above the last bound.
+        buckets: [AtomicU64; BUCKETS.len() + 1],
+        sum_nanos: AtomicU64,
+    }
+
+    impl AtomicHistogram {
+        const fn new() -> Self {
+            Self {
+                buckets: [const { AtomicU64::new(0) }; BUCKETS.len() + 1],
+                sum_nanos: AtomicU64::new(0),
+            }
+        }
+
+        fn record(&self, since: Timer) {
+            let nanos = now().saturating_sub(since.0);
+            let i = BUCKETS.partition_point(|&b| b < nanos);
+            self.buckets[i].fetch_add(1, Relaxed);
+            self.sum_nanos.fetch_add(nanos, Relaxed);
+        }
+
+        fn snapshot(&self) -> Histogram {
+            Histogram {
+                buckets: std::array::from_fn(|i| self.buckets[i].load(Relaxed)),
+                sum: Duration::from_nanos(self.sum_nanos.load(Relaxed)),
+            }
+        }
+    }
+
+    /// The statistics of one lock.
+    ///
+    /// Everything is Relaxed: these are just counters, they don't synchronize anything.
+    pub(crate) struct LockStats {
+        uncontended: AtomicU64,
+        contended: AtomicU64,
+        spins: AtomicU64,
+        futex_waits: AtomicU64,
+        futex_wakes: AtomicU64,
+        wait_time: AtomicHistogram,
+        hold_time: AtomicHistogram,
+        /// When the current exclusive owner got the lock.
+        held_since: AtomicU64,
+    }
+
+    impl LockStats {
+        pub(crate) const fn new() -> Self {
+            Self {
+                uncontended: AtomicU64::new(0),
+                contended: AtomicU64::new(0),
+                spins: AtomicU64::new(0),
+                futex_waits: AtomicU64::new(0),
+                futex_wakes: AtomicU64::new(0),
+                wait_time: AtomicHistogram::new(),
+                hold_time: AtomicHistogram::new(),
+                held_since: AtomicU64::new(0),
+            }
+        }
+
+        pub(crate) fn uncontended(&self) {
+            self.uncontended.fetch_add(1, Relaxed);
+        }
+
+        pub(crate) fn contended(&self) {
+            self.contended.fetch_add(1, Relaxed);
+        }
+
+        pub(crate) fn spins(&self, n: u32) {
+            if n > 0 {
+                self.spins.fetch_add(n.into(), Relaxed);
+            }
+        }
+
+        pub(crate) fn futex_wait(&self) {
+            self.futex_waits.fetch_add(1, Relaxed);
+        }
+
+        pub(crate) fn futex_wake(&self) {
+            self.futex_wakes.fetch_add(1, Relaxed);
+        }
+
+        /// Records how long a thread was blocked.
+        pub(crate) fn waited(&self, since: Timer) {
+            self.wait_time.record(since);
+        }
+
+        /// Called by an exclusive owner right after locking.
+        pub(crate) fn acquired(&self) {
+            self.held_since.store(now(), Relaxed);
+        }
+
+        /// Called by an exclusive owner right before unlocking.
+        pub(crate) fn released(&self) {
+            self.hold_time.record(Timer(self.held_since.load(Relaxed)));
+        }
+
+        pub(crate) fn snapshot(&self) -> StatsSnapshot {
+            StatsSnapshot {
+                uncontended: self.uncontended.load(Relaxed),
+                contended: self.contended.load(Relaxed),
+                spins: self.spins.load(Relaxed),
+                futex_waits: self.futex_waits.load(Relaxed),
+                futex_wakes: self.futex_wakes.load(Relaxed),
+                wait_time: self.wait_time.snapshot(),
+                hold_time: self.hold_time.snapshot(),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Histogram {
+        /// Counts per bucket, see `Histogram::bounds()`.
+        /// The last one counts everything above the last bound.
+        pub buckets: [u64; BUCKETS.len() + 1],
+        pub sum: Duration,
+    }
+
+    impl Histogram {
+        /// The upper bounds of the buckets.
+        pub fn bounds() -> impl Iterator<Item = Duration> {
+            BUCKETS.into_iter().map(Duration::from_nanos)
+        }
+
+        pub fn count(&self) -> u64 {
+            self.buckets.iter().sum()
+        }
+    }
+
+    /// A copy of the statistics of a lock at one point in time.
+    ///
+    /// Locks that aren't acquired, like `Condvar`, leave the acquisition counts at 0.
+    /// Hold times are only recorded for exclusive locks.
+    #[derive(Debug, Clone)]
+    pub struct StatsSnapshot {
+        pub uncontended: u64,
+        pub contended: u64,
+        /// Spin iterations before a thread got the lock or went to sleep.
+        pub spins: u64,
+        pub futex_waits: u64,
+        pub futex_wakes: u64,
+        pub wait_time: Histogram,
+        pub hold_time: Histogram,
+    }
+
+    impl StatsSnapshot {
+        /// Formats the statistics in the Prometheus text format,
+        /// with a `lock="<name>"` label on every line.
+        pub fn export(&self, name: &str) -> String {
+            let mut out = String::new();
+            let counters = [
+                (
+                    "lock_acquisitions_total",
+                    "kind=\"uncontended\",",
+                    self.uncontended,
+                ),
+                (
+                    "lock_acquisitions_total",
+                    "kind=\"contended\",",
+                    self.contended,
+                ),
+                ("lock_spins_total", "", self.spins),
+                ("lock_futex_waits_total", "", self.futex_waits),
+                ("lock_futex_wakes_total", "", self.futex_wakes),
+            ];
+            for (metric, labels, value) in counters {
+                writeln!(out, "{metric}{{{labels}lock=\"{name}\"}} {value}").unwrap();
+            }
+            for (metric, h) in [
+                ("lock_wait_seconds", &self.wait_time),
+                ("lock_hold_seconds", &self.hold_time),
+            ] {
+                let mut cumulative = 0;
+                for (bound, count) in Histogram::bounds().zip(h.buckets) {
+                    cumulative += count;
+                    let le = bound.as_secs_f64();
+                    writeln!(
+                        out,
+                        "{metric}_bucket{{lock=\"{name}\",le=\"{le}\"}} {cumulative}"
+                    )
+                    .unwrap();
+                }
+                let count = h.count();
+                writeln!(
+                    out,
+                    "{metric}_bucket{{lock=\"{name}\",le=\"+Inf\"}} {count}"
+                )
+                .unwrap();
+                let sum = h.sum.as_secs_f64();
+                writeln!(out, "{metric}_sum{{lock=\"{name}\"}} {sum}").unwrap();
+                writeln!(out, "{metric}_count{{lock=\"{name}\"}} {count}").unwrap();
+            }
+            out
+        }
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+mod disabled {
+    #[derive(Clone, Copy)]
+    pub(crate) struct Timer;
+
+    impl Timer {
+        #[inline(always)]
+        pub(crate) fn start() -> Self {
+            Self
+        }
+    }
+
+    pub(crate) struct LockStats;
+
+    impl LockStats {
+        pub(crate) const fn new() -> Self {
+            Self
+        }
+        #[inline(always)]
+        pub(crate) fn uncontended(&self) {}
+        #[inline(always)]
+        pub(crate) fn contended(&self) {}
+        #[inline(always)]
+        pub(crate) fn spins(&self, _n: u32) {}
+        #[inline(always)]
+        pub(crate) fn futex_wait(&self) {}
+        #[inline(always)]
+        pub(crate) fn futex_wake(&self) {}
+        #[inline(always)]
+        pub(crate) fn waited(&self, _since: super::Timer) {}
+        #[inline(always)]
+        pub(crate) fn acquired(&self) {}
+        #[inline(always)]
+        pub(crate) fn released(&self) {}
+    }
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod test {
+    use std::{thread, time::Duration};
+
+    use crate::{
+        condition_variable::Condvar, mutex::Mutex, read_write_lock::RwLock, spin::SpinLock,
+    };
+
+    #[test]
+    fn mutex_stats() {
+        let m = Mutex::new(0);
+        *m.lock().unwrap() += 1;
+        thread::scope(|s| {
+            let g = m.lock().unwrap();
+            s.spawn(|| *m.lock().unwrap() += 1);
+            thread::sleep(Duration::from_millis(50));
+            drop(g);
+        });
+        let stats = m.stats();
+        assert_eq!(stats.uncontended, 2);
+        assert_eq!(stats.contended, 1);
+        assert!(stats.futex_waits >= 1);
+        assert!(stats.futex_wakes >= 1);
+        assert_eq!(stats.wait_time.count(), 1);
+        assert!(stats.wait_time.sum >= Duration::from_millis(40));
+        assert_eq!(stats.hold_time.count(), 3);
+
+        let text = stats.export("m");
+        assert!(text.contains("lock_acquisitions_total{kind=\"contended\",lock=\"m\"} 1\n"));
+        assert!(text.contains("lock_hold_seconds_count{lock=\"m\"} 3\n"));
+    }
+
+    #[test]
+    fn other_stats() {
+        let rwlock = RwLock::new(0);
+        let spin = SpinLock::new(0);
+        let mutex = Mutex::new(false);
+        let condvar = Condvar::new();
+        drop(rwlock.read());
+        drop(rwlock.write());
+        drop(spin.lock());
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                *mutex.lock().unwrap() = true;
+                condvar.notify_one();
+            });
+            let mut g = mutex.lock().unwrap();
+            while !*g {
+                g = condvar.wait(g).unwrap();
+            }
+        });
+        assert_eq!(rwlock.stats().uncontended, 2);
+        assert_eq!(rwlock.stats().hold_time.count(), 1);
+        // Nobody was asleep, so no syscalls to wake them.
+        assert_eq!(rwlock.stats().futex_wakes, 0);
+        assert_eq!(spin.stats().uncontended, 1);
+        assert!(condvar.stats().futex_waits >= 1);
+        assert_eq!(
+            condvar.stats().wait_time.count(),
+            condvar.stats().futex_waits
+        );
+    }
+}
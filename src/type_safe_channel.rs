@@ -87,7 +87,8 @@ impl<T> Receiver<'_, T> {
 
 #[cfg(test)]
 mod test {
-    #[deny(warnings)]
+    #![deny(warnings)]
+
     use std::thread;
 
     use super::TypeSafeChannel;